@@ -69,7 +69,7 @@ pub fn get_chunks(text: &String,
         let mut previous_start = line_idx;
         while line_idx < lines.len() {
             let line = lines[line_idx];
-            let line_tok_n = count_text_tokens_with_fallback(tokenizer.clone(), line);
+            let line_tok_n = count_text_tokens_with_fallback(tokenizer.as_deref(), line);
 
             if !accum.is_empty() && current_tok_n + line_tok_n > tokens_limit {
                 let current_line = accum.iter().map(|(line, _)| line).join("\n");
@@ -104,7 +104,7 @@ pub fn get_chunks(text: &String,
         current_tok_n = 0;
         while line_idx >= 0 {
             let line = lines[line_idx as usize];
-            let text_orig_tok_n = count_text_tokens_with_fallback(tokenizer.clone(), line);
+            let text_orig_tok_n = count_text_tokens_with_fallback(tokenizer.as_deref(), line);
             if !accum.is_empty() && current_tok_n + text_orig_tok_n > tokens_limit {
                 let current_line = accum.iter().map(|(line, _)| line).join("\n");
                 let start_line = if use_symbol_range_always { top_row as u64 } else { accum.front().unwrap().1 as u64 };
@@ -174,7 +174,7 @@ mod tests {
     #[test]
     fn dummy_tokenizer_test() {
         let tokenizer = Arc::new(tokenizers::Tokenizer::from_str(DUMMY_TOKENIZER).unwrap());
-        let text_orig_tok_n = count_text_tokens(Some(tokenizer.clone()), PYTHON_CODE).unwrap();
+        let text_orig_tok_n = count_text_tokens(Some(&tokenizer), PYTHON_CODE).unwrap();
         assert_eq!(text_orig_tok_n, PYTHON_CODE.len());
     }
 
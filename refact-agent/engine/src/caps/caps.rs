@@ -44,6 +44,21 @@ pub struct BaseModelRecord {
     pub similar_models: Vec<String>,
     #[serde(default)]
     pub tokenizer: String,
+    /// More tokenizer sources to try in order when `tokenizer` fails to load
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tokenizer_fallbacks: Vec<String>,
+    /// Expected sha256 of the downloaded tokenizer file, hex encoded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokenizer_sha256: Option<String>,
+    /// Substituted for `$HF_REVISION` in `hf_tokenizer_template`, "main" if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokenizer_revision: Option<String>,
+    /// Substituted for `$HF_FILENAME` in `hf_tokenizer_template`, "tokenizer.json" if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokenizer_filename: Option<String>,
+    /// With `tokenizer_fallbacks`, try local files before downloading anything
+    #[serde(default)]
+    pub tokenizer_prefer_local: bool,
 
     #[serde(default = "default_true")]
     pub enabled: bool,
@@ -215,7 +230,7 @@ fn default_telemetry_retrieve_my_own() -> String {
 }
 
 pub fn default_hf_tokenizer_template() -> String {
-    "https://huggingface.co/$HF_MODEL/resolve/main/tokenizer.json".to_string()
+    "https://huggingface.co/$HF_MODEL/resolve/$HF_REVISION/$HF_FILENAME".to_string()
 }
 
 fn default_telemetry_basic_dest() -> String {
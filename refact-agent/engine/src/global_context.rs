@@ -7,9 +7,9 @@ use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex as StdMutex;
 use std::sync::RwLock as StdRwLock;
+use std::time::Duration;
 use hyper::StatusCode;
 use structopt::StructOpt;
-use tokenizers::Tokenizer;
 use tokio::signal;
 use tokio::sync::{Mutex as AMutex, RwLock as ARwLock, Semaphore};
 use tracing::{error, info};
@@ -23,6 +23,7 @@ use crate::files_in_workspace::DocumentsState;
 use crate::integrations::docker::docker_ssh_tunnel_utils::SshTunnel;
 use crate::integrations::sessions::IntegrationSession;
 use crate::privacy::PrivacySettings;
+use crate::tokens::{hf_hub_cache_dir, load_tokenizer_manifest, TokenizerDownloadPolicy, TokenizerRegistry, TokenizerRegistryConfig};
 use crate::telemetry::telemetry_structs;
 use crate::background_tasks::BackgroundTasksHolder;
 
@@ -109,6 +110,23 @@ pub struct CommandLine {
     pub active_group_id: Option<String>,
     #[structopt(long, help="Enable cloud threads support")]
     pub cloud_threads: bool,
+
+    #[structopt(long, help="Never download tokenizers, use only the ones already in the cache or given as local files.")]
+    pub tokenizer_offline: bool,
+    #[structopt(long, default_value="16", help="How many loaded tokenizers to keep in memory, least recently used ones are dropped first.")]
+    pub tokenizer_cache_size: usize,
+    #[structopt(long, help="Download tokenizers through this proxy, other requests don't use it.")]
+    pub tokenizer_proxy_url: Option<String>,
+    #[structopt(long, help="Trust this PEM certificate in addition to the system ones when downloading tokenizers.")]
+    pub tokenizer_ca_cert_path: Option<PathBuf>,
+    #[structopt(long, help="Write tokenizer downloads here before moving them into the cache, default is the cache dir itself.")]
+    pub tokenizer_tmp_dir: Option<PathBuf>,
+    #[structopt(long, help="JSON list of {\"model\": glob, \"tokenizer\": source} for models without a tokenizer, default is tokenizers.json in the config dir.")]
+    pub tokenizer_manifest: Option<PathBuf>,
+    #[structopt(long, default_value="10", help="Seconds to wait for a connection to a tokenizer host before retrying.")]
+    pub tokenizer_connect_timeout: u64,
+    #[structopt(long, default_value="60", help="Seconds a single tokenizer download attempt may take.")]
+    pub tokenizer_download_timeout: u64,
 }
 
 impl CommandLine {
@@ -160,8 +178,7 @@ pub struct GlobalContext {
     pub caps_reading_lock: Arc<AMutex<bool>>,
     pub caps_last_error: String,
     pub caps_last_attempted_ts: u64,
-    pub tokenizer_map: HashMap<String, Option<Arc<Tokenizer>>>,
-    pub tokenizer_download_lock: Arc<AMutex<bool>>,
+    pub tokenizer_registry: Arc<TokenizerRegistry>,
     pub completions_cache: Arc<StdRwLock<CompletionCache>>,
     pub telemetry: Arc<StdRwLock<telemetry_structs::Storage>>,
     pub vec_db: Arc<AMutex<Option<crate::vecdb::vdb_highlev::VecDb>>>,
@@ -398,6 +415,22 @@ pub async fn create_global_context(
         let path = crate::files_correction::canonical_path(&cmdline.workspace_folder);
         workspace_dirs = vec![path];
     }
+    let tokenizer_config = TokenizerRegistryConfig {
+        cache_dir: cache_dir.clone(),
+        insecure: cmdline.insecure,
+        hf_hub_cache: hf_hub_cache_dir(|name| std::env::var(name).ok()),
+        offline: cmdline.tokenizer_offline,
+        download_policy: TokenizerDownloadPolicy {
+            connect_timeout: Duration::from_secs(cmdline.tokenizer_connect_timeout),
+            request_timeout: Duration::from_secs(cmdline.tokenizer_download_timeout),
+            ..Default::default()
+        },
+        proxy_url: cmdline.tokenizer_proxy_url.clone(),
+        ca_cert_path: cmdline.tokenizer_ca_cert_path.clone(),
+        tmp_dir: cmdline.tokenizer_tmp_dir.clone(),
+        manifest: load_tokenizer_manifest(cmdline.tokenizer_manifest.as_deref(), &config_dir),
+    };
+    let tokenizer_registry = TokenizerRegistry::new(tokenizer_config, cmdline.tokenizer_cache_size);
     let cx = GlobalContext {
         shutdown_flag: Arc::new(AtomicBool::new(false)),
         cmdline: cmdline.clone(),
@@ -409,8 +442,7 @@ pub async fn create_global_context(
         caps_reading_lock: Arc::new(AMutex::<bool>::new(false)),
         caps_last_error: String::new(),
         caps_last_attempted_ts: 0,
-        tokenizer_map: HashMap::new(),
-        tokenizer_download_lock: Arc::new(AMutex::<bool>::new(false)),
+        tokenizer_registry: Arc::new(tokenizer_registry),
         completions_cache: Arc::new(StdRwLock::new(CompletionCache::new())),
         telemetry: Arc::new(StdRwLock::new(telemetry_structs::Storage::new())),
         vec_db: Arc::new(AMutex::new(None)),
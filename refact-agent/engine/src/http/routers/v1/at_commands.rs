@@ -129,11 +129,11 @@ pub async fn handle_v1_command_completion(
         .unwrap())
 }
 
-async fn count_tokens(tokenizer_arc: Option<Arc<Tokenizer>>, messages: &Vec<ChatMessage>) -> Result<u64, ScratchError> {
+async fn count_tokens(tokenizer: Option<&Tokenizer>, messages: &Vec<ChatMessage>) -> Result<u64, ScratchError> {
     let mut accum: u64 = 0;
 
     for message in messages {
-        accum += message.content.count_tokens(tokenizer_arc.clone(), &None)
+        accum += message.content.count_tokens(tokenizer, &None)
             .map_err(|e| ScratchError {
                 status_code: StatusCode::INTERNAL_SERVER_ERROR,
                 message: format!("v1_chat_token_counter: count_tokens failed: {}", e),
@@ -174,8 +174,8 @@ pub async fn handle_v1_command_preview(
     let tokenizer_arc = match tokens::cached_tokenizer(global_context.clone(), &model_rec.base).await {
         Ok(x) => x,
         Err(e) => {
-            tracing::error!(e);
-            return Err(ScratchError::new(StatusCode::BAD_REQUEST, e));
+            tracing::error!("{e}");
+            return Err(ScratchError::new(StatusCode::BAD_REQUEST, e.to_string()));
         }
     };
 
@@ -254,7 +254,7 @@ pub async fn handle_v1_command_preview(
     } else {
         preview.clone()
     };
-    let tokens_number = count_tokens(tokenizer_arc.clone(), &messages_to_count).await?;
+    let tokens_number = count_tokens(tokenizer_arc.as_deref(), &messages_to_count).await?;
 
     Ok(Response::builder()
         .status(StatusCode::OK)
@@ -279,7 +279,7 @@ pub async fn handle_v1_at_command_execute(
         .map_err(|e| ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, e))?;
 
     let tokenizer = tokens::cached_tokenizer(global_context.clone(), &model_rec.base).await
-        .map_err(|e| ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+        .map_err(|e| ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
     let mut ccx = AtCommandsContext::new(
         global_context.clone(),
@@ -259,7 +259,7 @@ async fn pp_limit_and_merge(
         if !line_ref.take_ignoring_floor && line_ref.useful <= settings.take_floor {
             continue;
         }
-        let mut ntokens = count_text_tokens_with_fallback(tokenizer.clone(), &line_ref.line_content);
+        let mut ntokens = count_text_tokens_with_fallback(tokenizer.as_deref(), &line_ref.line_content);
 
         if !files_mentioned_set.contains(&line_ref.file_ref.cpath) {
             if files_mentioned_set.len() >= settings.max_files_n {
@@ -268,7 +268,7 @@ async fn pp_limit_and_merge(
             files_mentioned_set.insert(line_ref.file_ref.cpath.clone());
             files_mentioned_sequence.push(line_ref.file_ref.cpath.clone());
             if !single_file_mode {
-                ntokens += count_text_tokens_with_fallback(tokenizer.clone(), &line_ref.file_ref.cpath.as_str());
+                ntokens += count_text_tokens_with_fallback(tokenizer.as_deref(), &line_ref.file_ref.cpath.as_str());
                 ntokens += 5;  // a margin for any overhead: file_sep, new line, etc
             }
         }
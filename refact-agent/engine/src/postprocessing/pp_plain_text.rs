@@ -14,7 +14,7 @@ fn limit_text_content(
 ) -> String {
     let mut new_text_lines = vec![];
     for line in text.lines() {
-        let line_tokens = count_text_tokens_with_fallback(tokenizer.clone(), &line);
+        let line_tokens = count_text_tokens_with_fallback(tokenizer.as_deref(), &line);
         if *tok_used + line_tokens > tok_per_m {
             if new_text_lines.is_empty() {
                 new_text_lines.push("No content: tokens limit reached");
@@ -39,7 +39,7 @@ pub async fn postprocess_plain_text(
     }
     let mut messages_sorted = plain_text_messages;
     let messages_len = messages_sorted.len();
-    messages_sorted.sort_by(|a, b| a.content.size_estimate(tokenizer.clone(), style).cmp(&b.content.size_estimate(tokenizer.clone(), style)));
+    messages_sorted.sort_by(|a, b| a.content.size_estimate(tokenizer.as_deref(), style).cmp(&b.content.size_estimate(tokenizer.as_deref(), style)));
 
     let mut tok_used_global = 0;
     let mut tok_per_m = tokens_limit / messages_len;
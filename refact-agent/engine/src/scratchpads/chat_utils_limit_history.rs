@@ -117,7 +117,7 @@ fn compress_message_at_index(
     token_cache.invalidate(&mutable_messages[index]);
     let (extra_tokens_per_message, _) = get_model_token_params(model_id);
     // Recalculate token usage after compression using the cache
-    token_counts[index] = token_cache.get_token_count(&mutable_messages[index], t.tokenizer.clone(), extra_tokens_per_message)?;
+    token_counts[index] = token_cache.get_token_count(&mutable_messages[index], t.tokenizer.as_deref(), extra_tokens_per_message)?;
     Ok(token_counts[index])
 }
 
@@ -520,7 +520,7 @@ pub fn fix_and_limit_messages_history(
     let mut token_cache = TokenCountCache::new();
     let mut token_counts: Vec<i32> = Vec::with_capacity(mutable_messages.len());
     for msg in &mutable_messages {
-        let count = token_cache.get_token_count(msg, t.tokenizer.clone(), extra_tokens_per_message)?;
+        let count = token_cache.get_token_count(msg, t.tokenizer.as_deref(), extra_tokens_per_message)?;
         token_counts.push(count);
     }
     let tools_description_tokens = if let Some(desc) = tools_description.clone() {
@@ -1,128 +1,679 @@
 use tokio::io::AsyncWriteExt;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+use std::future::Future;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{Mutex as StdMutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::RwLock as ARwLock;
 use tokio::sync::Mutex as AMutex;
-use tokenizers::Tokenizer;
-use reqwest::header::AUTHORIZATION;
-use reqwest::Response;
+use tokenizers::utils::padding::pad_encodings;
+use tokenizers::utils::truncation::truncate_encodings;
+use tokenizers::{Encoding, Model, ModelWrapper, NormalizedString, Normalizer, OffsetReferential, OffsetType, PreTokenizedString, PostProcessor, PreTokenizer, PaddingParams, PaddingStrategy, Tokenizer, TruncationParams};
+use reqwest::header::{HeaderName, AUTHORIZATION, CONTENT_RANGE, CONTENT_TYPE, COOKIE, ETAG, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, PROXY_AUTHORIZATION, RANGE};
+use reqwest::{Response, StatusCode};
+use sha2::{Digest, Sha256};
+use rand::Rng;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
+use lazy_static::lazy_static;
+use futures::StreamExt;
+use percent_encoding::percent_decode_str;
+use base64::Engine;
+use tokenizers::decoders::byte_level::ByteLevel as ByteLevelDecoder;
+use tokenizers::models::bpe::BPE;
+use tokenizers::models::wordlevel::WordLevel;
+use tokenizers::pre_tokenizers::byte_level::ByteLevel;
+use tokenizers::pre_tokenizers::split::{Split, SplitPattern};
+use tokenizers::SplitDelimiterBehavior;
+use tokenizers::DecoderWrapper;
 
-use crate::custom_error::MapErrToString;
+pub use crate::tokens::manifest::{load_tokenizer_manifest, TokenizerManifest};
+use crate::call_validation::ChatMessage;
 use crate::files_correction::canonical_path;
-use crate::global_context::GlobalContext;
-use crate::caps::{default_hf_tokenizer_template, strip_model_from_finetune, BaseModelRecord};
+use crate::global_context::{try_load_caps_quickly_if_not_present, GlobalContext};
+use crate::caps::{default_hf_tokenizer_template, resolve_model, strip_model_from_finetune, BaseModelRecord, CodeAssistantCaps};
 
+mod gguf;
+mod manifest;
+#[cfg(test)]
+pub mod test_support;
 
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenizerError {
+    Download(String),
+    Parse(String),
+    UnsupportedFormat(String),
+    Io(String),
+    EmptyTokenizer(String),
+    NotFound(String),
+    /// The server answered with an HTML page, usually a login page of a proxy, retrying won't help
+    HtmlResponse(String),
+    /// The response is bigger than `max_tokenizer_bytes`, retrying won't help either
+    TooLarge(String),
+    /// The caller went away and cancelled the load
+    Cancelled,
+}
+
+impl fmt::Display for TokenizerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenizerError::Download(msg) => write!(f, "failed to download tokenizer: {msg}"),
+            TokenizerError::Parse(msg) => write!(f, "failed to load tokenizer: {msg}"),
+            TokenizerError::UnsupportedFormat(msg) => write!(f, "unsupported tokenizer format: {msg}"),
+            TokenizerError::Io(msg) => write!(f, "tokenizer io error: {msg}"),
+            TokenizerError::EmptyTokenizer(model_id) => write!(f, "failed to load tokenizer: empty tokenizer for {model_id}"),
+            TokenizerError::NotFound(msg) => write!(f, "tokenizer not found: {msg}"),
+            TokenizerError::HtmlResponse(url) => write!(
+                f, "got an HTML page instead of a tokenizer from {url}, check authentication or proxy settings"
+            ),
+            TokenizerError::TooLarge(msg) => write!(f, "tokenizer is too large: {msg}"),
+            TokenizerError::Cancelled => write!(f, "tokenizer loading was cancelled"),
+        }
+    }
+}
+
+impl Error for TokenizerError {}
+
+impl From<TokenizerError> for String {
+    fn from(err: TokenizerError) -> Self {
+        err.to_string()
+    }
+}
+
+pub const DEFAULT_MAX_TOKENIZER_BYTES: u64 = 50 * 1024 * 1024;
+
+pub const DEFAULT_TOKENIZER_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_TOKENIZER_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How `try_download_tokenizer_file_and_open` retries a failed download
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenizerDownloadPolicy {
+    pub max_attempts: usize,
+    pub initial_backoff: Duration,
+    pub backoff_multiplier: f64,
+    pub max_backoff: Duration,
+    /// Bigger responses are aborted, a misconfigured URL can point at model weights
+    pub max_tokenizer_bytes: u64,
+    /// Time to open the connection, an unreachable host fails the attempt after this.
+    /// Set on the http client, so a slow but healthy server is bounded by `request_timeout` only
+    pub connect_timeout: Duration,
+    /// Time for the whole attempt, body included
+    pub request_timeout: Duration,
+}
+
+impl Default for TokenizerDownloadPolicy {
+    fn default() -> Self {
+        TokenizerDownloadPolicy {
+            max_attempts: 15,
+            initial_backoff: Duration::from_millis(200),
+            backoff_multiplier: 1.0,
+            max_backoff: Duration::from_millis(200),
+            max_tokenizer_bytes: DEFAULT_MAX_TOKENIZER_BYTES,
+            connect_timeout: DEFAULT_TOKENIZER_CONNECT_TIMEOUT,
+            request_timeout: DEFAULT_TOKENIZER_REQUEST_TIMEOUT,
+        }
+    }
+}
+
+impl TokenizerDownloadPolicy {
+    /// Delay before the given retry (1 is the first retry), exponential and capped by `max_backoff`,
+    /// with jitter: the result is between half and all of the capped delay
+    pub fn backoff(&self, retry: usize) -> Duration {
+        let exponent = retry.saturating_sub(1).min(i32::MAX as usize) as i32;
+        let delay = self.initial_backoff.as_secs_f64() * self.backoff_multiplier.max(1.0).powi(exponent);
+        let delay = delay.min(self.max_backoff.as_secs_f64());
+        let jitter = rand::thread_rng().gen_range(0.5..=1.0);
+        Duration::from_secs_f64(delay * jitter)
+    }
+}
+
+pub const TOKENIZER_CACHE_CAPACITY: usize = 16;
+
+/// Loaded tokenizers by model id, the least recently used one is evicted when the cache is full.
+/// `None` is a valid entry, it means the model has no tokenizer and token counts are estimated.
+pub struct TokenizerCache {
+    pub capacity: usize,
+    map: HashMap<String, Option<Arc<Tokenizer>>>,
+    in_used_order: Vec<String>,  // least recently used first
+}
+
+impl TokenizerCache {
+    pub fn new(capacity: usize) -> Self {
+        TokenizerCache { capacity, map: HashMap::new(), in_used_order: Vec::new() }
+    }
+
+    pub fn get(&mut self, model_id: &str) -> Option<Option<Arc<Tokenizer>>> {
+        let tokenizer = self.map.get(model_id).cloned()?;
+        self.touch(model_id);
+        Some(tokenizer)
+    }
+
+    pub fn insert(&mut self, model_id: String, tokenizer: Option<Arc<Tokenizer>>) {
+        self.map.insert(model_id.clone(), tokenizer);
+        self.touch(&model_id);
+        while self.in_used_order.len() > self.capacity {
+            let evicted = self.in_used_order.remove(0);
+            self.map.remove(&evicted);
+            tracing::info!("evicted tokenizer for {} from memory", evicted);
+        }
+    }
+
+    pub fn remove(&mut self, model_id: &str) -> Option<Option<Arc<Tokenizer>>> {
+        self.in_used_order.retain(|id| id != model_id);
+        self.map.remove(model_id)
+    }
+
+    pub fn model_ids(&self) -> impl Iterator<Item = &String> {
+        self.map.keys()
+    }
+
+    pub fn contains_key(&self, model_id: &str) -> bool {
+        self.map.contains_key(model_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    fn touch(&mut self, model_id: &str) {
+        self.in_used_order.retain(|id| id != model_id);
+        self.in_used_order.push(model_id.to_string());
+    }
+}
+
+/// In-process counters of how `cached_tokenizer` gets its tokenizers
+#[derive(Debug, Default)]
+pub struct TokenizerMetrics {
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    downloads: AtomicU64,
+    download_failures: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenizerMetricsSnapshot {
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub downloads: u64,
+    pub download_failures: u64,
+}
+
+impl TokenizerMetrics {
+    pub fn snapshot(&self) -> TokenizerMetricsSnapshot {
+        TokenizerMetricsSnapshot {
+            cache_hits: self.cache_hits.load(AtomicOrdering::Relaxed),
+            cache_misses: self.cache_misses.load(AtomicOrdering::Relaxed),
+            downloads: self.downloads.load(AtomicOrdering::Relaxed),
+            download_failures: self.download_failures.load(AtomicOrdering::Relaxed),
+        }
+    }
+}
+
+/// One download lock per model: different tokenizers download in parallel,
+/// concurrent requests for the same tokenizer wait for the first one
+#[derive(Default)]
+pub struct TokenizerDownloadLocks {
+    locks: StdMutex<HashMap<String, Arc<AMutex<()>>>>,
+}
+
+impl TokenizerDownloadLocks {
+    pub fn lock_for(&self, model_id: &str) -> Arc<AMutex<()>> {
+        self.locks.lock().unwrap().entry(model_id.to_string()).or_default().clone()
+    }
+}
+
+/// Called with the number of bytes downloaded so far and the `Content-Length`, if the server sent one
+pub type TokenizerDownloadProgress = Arc<dyn Fn(u64, Option<u64>) + Send + Sync>;
+
+/// Writes the body of `res` into `to`, appending after `resume_from` bytes already there if it's a `206 Partial Content`
 async fn try_open_tokenizer(
     res: Response,
     to: impl AsRef<Path>,
-) -> Result<(), String> {
+    progress: Option<&TokenizerDownloadProgress>,
+    max_bytes: u64,
+    resume_from: u64,
+) -> Result<(), TokenizerError> {
+    let resume_from = if res.status() == StatusCode::PARTIAL_CONTENT { resume_from } else { 0 };
     let mut file = tokio::fs::OpenOptions::new()
         .write(true)
         .create(true)
+        .append(resume_from > 0)
+        .truncate(resume_from == 0)
         .open(&to)
         .await
-        .map_err(|e| format!("failed to open file: {}", e))?;
-    file.write_all(&res.bytes().await
-        .map_err(|e| format!("failed to fetch bytes: {}", e))?
-    ).await.map_err(|e| format!("failed to write to file: {}", e))?;
-    file.flush().await.map_err(|e| format!("failed to flush file: {}", e))?;
+        .map_err(|e| TokenizerError::Io(format!("failed to open file: {}", e)))?;
+    let url = res.url().to_string();
+    let total = res.content_length().map(|len| resume_from + len);
+    let mut downloaded = resume_from;
+    let mut stream = res.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| TokenizerError::Download(format!("failed to fetch bytes: {}", e)))?;
+        if downloaded == 0 && looks_like_html(&chunk) {
+            return Err(TokenizerError::HtmlResponse(url));
+        }
+        downloaded += chunk.len() as u64;
+        if downloaded > max_bytes {
+            return Err(TokenizerError::TooLarge(format!("{url} sent more than {max_bytes} bytes")));
+        }
+        file.write_all(&chunk).await.map_err(|e| TokenizerError::Io(format!("failed to write to file: {}", e)))?;
+        if let Some(progress) = progress {
+            progress(downloaded, total);
+        }
+    }
+    file.flush().await.map_err(|e| TokenizerError::Io(format!("failed to flush file: {}", e)))?;
     tracing::info!("saved tokenizer to {}", to.as_ref().display());
     Ok(())
 }
 
+/// `tokenizer_api_key` forms:
+/// * `token` -- `Authorization: Bearer token`
+/// * `Basic dXNlcjpwYXNz` (any `<scheme> <credentials>`) -- sent as `Authorization` as is
+/// * `Header:X-Api-Key=secret` -- `X-Api-Key: secret`
+fn tokenizer_auth_header(tokenizer_api_key: &str) -> Result<Option<(HeaderName, String)>, TokenizerError> {
+    let key = tokenizer_api_key.trim();
+    if key.is_empty() {
+        return Ok(None);
+    }
+    if let Some(custom) = key.strip_prefix("Header:") {
+        let (name, value) = custom.split_once('=')
+            .ok_or_else(|| TokenizerError::Download("tokenizer api key \"Header:\" must look like Header:Name=value".to_string()))?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|e| TokenizerError::Download(format!("invalid tokenizer api key header name \"{}\": {}", name.trim(), e)))?;
+        return Ok(Some((name, value.trim().to_string())));
+    }
+    if key.contains(char::is_whitespace) {
+        return Ok(Some((AUTHORIZATION, key.to_string())));
+    }
+    Ok(Some((AUTHORIZATION, format!("Bearer {key}"))))
+}
+
+/// What to log instead of a header value. Credentials are never logged, for `Authorization` only the scheme is kept
+fn redact_header_value(name: &HeaderName, value: &str) -> String {
+    const REDACTED: &str = "<redacted>";
+    if name == AUTHORIZATION || name == PROXY_AUTHORIZATION {
+        return match value.trim().split_once(char::is_whitespace) {
+            Some((scheme, _)) => format!("{scheme} {REDACTED}"),
+            None => REDACTED.to_string(),
+        };
+    }
+    let name = name.as_str();
+    if name == COOKIE.as_str() || ["key", "token", "secret", "auth", "password"].iter().any(|word| name.contains(word)) {
+        return REDACTED.to_string();
+    }
+    value.to_string()
+}
+
+fn looks_like_html(body: &[u8]) -> bool {
+    let start = body.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(body.len());
+    let head = &body[start..body.len().min(start + 16)];
+    [b"<!doctype".as_slice(), b"<html".as_slice()].iter()
+        .any(|prefix| head.len() >= prefix.len() && head[..prefix.len()].eq_ignore_ascii_case(prefix))
+}
+
+/// The ETag of a cached tokenizer is stored next to it, e.g. `tokenizer.json.etag`
+fn etag_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".etag");
+    path.with_file_name(file_name)
+}
+
+/// What the response an interrupted download into `to` came from is checked against when resuming it,
+/// kept next to it, e.g. `<uuid>.tmp.if-range`
+fn if_range_path(to: &Path) -> PathBuf {
+    let mut file_name = to.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".if-range");
+    to.with_file_name(file_name)
+}
+
+/// The strong ETag of `res`, or its Last-Modified, the validators `If-Range` accepts
+fn if_range_validator(res: &Response) -> Option<String> {
+    let header = |name| res.headers().get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty());
+    header(ETAG).filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| header(LAST_MODIFIED))
+        .map(str::to_string)
+}
+
+/// The first byte of `Content-Range: bytes <first>-<last>/<total>`
+fn content_range_start(res: &Response) -> Option<u64> {
+    res.headers().get(CONTENT_RANGE)?.to_str().ok()?
+        .trim()
+        .strip_prefix("bytes ")?
+        .split('-').next()?
+        .trim()
+        .parse().ok()
+}
+
+/// Downloads into `to`, returns the ETag of the response if the server sent one.
+/// When `cached_path` is a usable tokenizer (matching `expected_sha256`, if given) with its ETag, the request is
+/// conditional, and `304 Not Modified` is served by copying `cached_path` into `to`.
+#[allow(clippy::too_many_arguments)]
 async fn download_tokenizer_file(
     http_client: &reqwest::Client,
     http_path: &str,
     tokenizer_api_token: &str,
     to: &Path,
-) -> Result<(), String> {
+    cached_path: &Path,
+    expected_sha256: Option<&str>,
+    progress: Option<&TokenizerDownloadProgress>,
+    policy: &TokenizerDownloadPolicy,
+) -> Result<Option<String>, TokenizerError> {
     tokio::fs::create_dir_all(
-        to.parent().ok_or_else(|| "tokenizer path has no parent")?,
-    ).await.map_err(|e| format!("failed to create parent dir: {}", e))?;
-    if to.exists() {
-        return Ok(());
+        to.parent().ok_or_else(|| TokenizerError::Io("tokenizer path has no parent".to_string()))?,
+    ).await.map_err(|e| TokenizerError::Io(format!("failed to create parent dir: {}", e)))?;
+    // what an interrupted attempt left in `to` is kept, and only the rest is requested if the file is still
+    // the same one, `If-Range` gets the whole file otherwise. Without a validator it's downloaded again
+    let partial_len = tokio::fs::metadata(to).await.map(|m| m.len()).unwrap_or(0);
+    let if_range = match partial_len {
+        0 => None,
+        _ => tokio::fs::read_to_string(if_range_path(to)).await.ok()
+            .map(|validator| validator.trim().to_string())
+            .filter(|validator| !validator.is_empty()),
+    };
+    let resume_from = if if_range.is_some() { partial_len } else { 0 };
+
+    let mut req = http_client.get(http_path).timeout(policy.request_timeout);
+
+    if let Some((header, value)) = tokenizer_auth_header(tokenizer_api_token)? {
+        tracing::debug!("tokenizer request header {}: {}", header, redact_header_value(&header, &value));
+        req = req.header(header, value)
+    }
+    if let Some(validator) = &if_range {
+        tracing::info!("resuming tokenizer download from {} at byte {}", http_path, resume_from);
+        req = req.header(RANGE, format!("bytes={resume_from}-")).header(IF_RANGE, validator.as_str());
+    } else {
+        tracing::info!("downloading tokenizer from {}", http_path);
     }
 
-    tracing::info!("downloading tokenizer from {}", http_path);
-    let mut req = http_client.get(http_path);
-    
-    if !tokenizer_api_token.is_empty() {
-        req = req.header(AUTHORIZATION, format!("Bearer {tokenizer_api_token}"))
+    // a broken cached copy is what the download has to replace, "not modified" can't keep it
+    let cached_is_usable = resume_from == 0
+        && check_json_file(cached_path).is_ok()
+        && check_sha256(cached_path, expected_sha256).await.is_ok();
+    let cached_etag = if cached_is_usable {
+        tokio::fs::read_to_string(etag_path(cached_path)).await.ok()
+            .map(|etag| etag.trim().to_string())
+            .filter(|etag| !etag.is_empty())
+    } else {
+        None
+    };
+    if let Some(etag) = &cached_etag {
+        req = req.header(IF_NONE_MATCH, etag.as_str());
     }
-    
-    let res = req
-        .send()
-        .await
-        .map_err(|e| format!("failed to get response: {}", e))?
+
+    let res = req.send().await
+        .map_err(|e| TokenizerError::Download(format!("failed to get response: {}", e)))?;
+    if res.status() == StatusCode::NOT_MODIFIED && cached_etag.is_some() {
+        tracing::info!("tokenizer at {} is not modified, reusing {}", http_path, cached_path.display());
+        tokio::fs::copy(cached_path, to).await
+            .map_err(|e| TokenizerError::Io(format!("failed to copy cached tokenizer: {}", e)))?;
+        return Ok(cached_etag);
+    }
+    if res.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        let _ = tokio::fs::remove_file(to).await;
+        let _ = tokio::fs::remove_file(if_range_path(to)).await;
+        return Err(TokenizerError::Download(format!("{http_path} can't resume at byte {resume_from}, starting over")));
+    }
+    let res = res
         .error_for_status()
-        .map_err(|e| format!("failed to get response: {}", e))?;
-    try_open_tokenizer(res, to).await?;
+        .map_err(|e| TokenizerError::Download(format!("failed to get response: {}", e)))?;
+    let is_html = res.headers().get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim_start().to_ascii_lowercase().starts_with("text/html"));
+    if is_html {
+        return Err(TokenizerError::HtmlResponse(http_path.to_string()));
+    }
+    let max_bytes = policy.max_tokenizer_bytes;
+    // a server that ignores the Range header, or has a different file now, sends the whole file with 200 OK
+    let resumed = if res.status() == StatusCode::PARTIAL_CONTENT { resume_from } else { 0 };
+    if resumed > 0 && content_range_start(&res) != Some(resumed) {
+        let _ = tokio::fs::remove_file(to).await;
+        let _ = tokio::fs::remove_file(if_range_path(to)).await;
+        return Err(TokenizerError::Download(format!("{http_path} didn't resume at byte {resumed}, starting over")));
+    }
+    if let Some(content_length) = res.content_length().filter(|len| resumed + *len > max_bytes) {
+        return Err(TokenizerError::TooLarge(format!("{http_path} is {content_length} bytes, the limit is {max_bytes}")));
+    }
+    let etag = res.headers().get(ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+    match if_range_validator(&res) {
+        Some(validator) => { let _ = tokio::fs::write(if_range_path(to), validator).await; }
+        None => { let _ = tokio::fs::remove_file(if_range_path(to)).await; }
+    }
+    try_open_tokenizer(res, to, progress, max_bytes, resume_from).await?;
+    Ok(etag)
+}
+
+async fn file_sha256(path: &Path) -> Result<String, TokenizerError> {
+    let bytes = tokio::fs::read(path).await
+        .map_err(|e| TokenizerError::Io(format!("failed to read {}: {}", path.display(), e)))?;
+    let mut hasher = Sha256::new();
+    hasher.update(&bytes);
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+async fn check_sha256(path: &Path, expected_sha256: Option<&str>) -> Result<(), TokenizerError> {
+    let Some(expected) = expected_sha256 else {
+        return Ok(());
+    };
+    let actual = file_sha256(path).await?;
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        return Err(TokenizerError::Parse(format!("checksum mismatch, expected sha256 {expected}, got {actual}")));
+    }
     Ok(())
 }
 
-fn check_json_file(path: &Path) -> bool {
-    match Tokenizer::from_file(path) {
-        Ok(_) => { true }
-        Err(_) => { false }
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Mirrors serve `tokenizer.json.gz` or `.zst`, and reqwest doesn't undo `Content-Encoding` for us,
+/// so compressed downloads are recognized by their magic bytes and unpacked in place.
+/// Returns whether the file was compressed, the checksum is then checked against the unpacked tokenizer.
+fn decompress_tokenizer_file(path: &Path, max_bytes: u64) -> Result<bool, TokenizerError> {
+    let io_err = |e: std::io::Error| TokenizerError::Io(format!("failed to read {}: {}", path.display(), e));
+    let mut magic = [0u8; 4];
+    let magic_len = std::fs::File::open(path).and_then(|mut f| f.read(&mut magic)).map_err(io_err)?;
+    let file = std::fs::File::open(path).map_err(io_err)?;
+    let decoder: Box<dyn Read> = if magic[..magic_len].starts_with(GZIP_MAGIC) {
+        Box::new(flate2::read::GzDecoder::new(file))
+    } else if magic[..magic_len].starts_with(ZSTD_MAGIC) {
+        Box::new(zstd::stream::read::Decoder::new(file).map_err(io_err)?)
+    } else {
+        return Ok(false);
+    };
+    // a small archive can unpack into something huge
+    let mut unpacked = Vec::new();
+    decoder.take(max_bytes + 1).read_to_end(&mut unpacked)
+        .map_err(|e| TokenizerError::Parse(format!("failed to decompress {}: {}", path.display(), e)))?;
+    if unpacked.len() as u64 > max_bytes {
+        return Err(TokenizerError::TooLarge(format!("{} unpacks to more than {} bytes", path.display(), max_bytes)));
+    }
+    std::fs::write(path, unpacked).map_err(io_err)?;
+    Ok(true)
+}
+
+/// A file can parse and still be useless (e.g. an empty vocab), so a trial encode must produce some ids
+fn check_json_file(path: &Path) -> Result<(), TokenizerError> {
+    let bytes = std::fs::read(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => TokenizerError::NotFound(path.display().to_string()),
+        _ => TokenizerError::Io(format!("failed to read {}: {}", path.display(), e)),
+    })?;
+    let tokenizer = Tokenizer::from_bytes(&bytes)
+        .map_err(|e| TokenizerError::Parse(format!("{} is not a tokenizer: {}", path.display(), e)))?;
+    match tokenizer.encode("hello world", false) {
+        Ok(encoding) if !encoding.get_ids().is_empty() => Ok(()),
+        Ok(_) => Err(TokenizerError::Parse(format!("{} encodes \"hello world\" to no tokens", path.display()))),
+        Err(e) => Err(TokenizerError::Parse(format!("{} fails to encode \"hello world\": {}", path.display(), e))),
+    }
+}
+
+/// Deletes the download temp file on every way out of `try_download_tokenizer_file_and_open`,
+/// after a successful move there's nothing left to delete
+struct TmpFileGuard(PathBuf);
+
+impl Drop for TmpFileGuard {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+        let _ = std::fs::remove_file(if_range_path(&self.0));
+    }
+}
+
+/// Renames when possible, so `to` is never seen half-written. Across filesystems the file is copied
+/// next to `to`, synced and renamed over it.
+async fn move_tokenizer_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if tokio::fs::rename(from, to).await.is_ok() {
+        return Ok(());
+    }
+    let part = to.with_extension("part");
+    let copied = async {
+        tokio::fs::copy(from, &part).await?;
+        tokio::fs::File::open(&part).await?.sync_all().await?;
+        tokio::fs::rename(&part, to).await
+    }.await;
+    if copied.is_err() {
+        let _ = tokio::fs::remove_file(&part).await;
+    } else {
+        let _ = tokio::fs::remove_file(from).await;
+    }
+    copied
+}
+
+/// The configured dir, otherwise the directory of the cached tokenizer so moving the download there stays
+/// on one filesystem, the system temp dir is the last resort if neither can be created
+async fn tokenizer_download_tmp_dir(configured: Option<&Path>, path: &Path) -> PathBuf {
+    for dir in configured.into_iter().chain(path.parent()) {
+        match tokio::fs::create_dir_all(dir).await {
+            Ok(()) => return dir.to_path_buf(),
+            Err(e) => tracing::warn!("can't use {} for tokenizer downloads: {}", dir.display(), e),
+        }
+    }
+    std::env::temp_dir()
+}
+
+/// Runs `fut` unless `cancel` fires first
+async fn cancellable<T>(cancel: Option<&CancellationToken>, fut: impl Future<Output = T>) -> Result<T, TokenizerError> {
+    match cancel {
+        Some(cancel) => tokio::select! {
+            _ = cancel.cancelled() => Err(TokenizerError::Cancelled),
+            output = fut => Ok(output),
+        },
+        None => Ok(fut.await),
     }
 }
 
+/// Returns whether the file was downloaded, `false` means a valid cached copy was already there
+#[allow(clippy::too_many_arguments)]
 async fn try_download_tokenizer_file_and_open(
     http_client: &reqwest::Client,
     http_path: &str,
     tokenizer_api_token: &str,
     path: &Path,
-) -> Result<(), String> {
-    if path.exists() && check_json_file(path) {
-        return Ok(());
+    expected_sha256: Option<&str>,
+    offline: bool,
+    policy: &TokenizerDownloadPolicy,
+    tmp_dir: Option<&Path>,
+    progress: Option<&TokenizerDownloadProgress>,
+    cancel: Option<&CancellationToken>,
+) -> Result<bool, TokenizerError> {
+    if check_json_file(path).is_ok() && check_sha256(path, expected_sha256).await.is_ok() {
+        return Ok(false);
+    }
+    if offline {
+        return Err(TokenizerError::NotFound(format!(
+            "{} is not in the cache, and downloading {} is disabled in offline mode", path.display(), http_path
+        )));
     }
 
-    let tmp_file = std::env::temp_dir().join(Uuid::new_v4().to_string());
-    let tmp_path = tmp_file.as_path();
+    let tmp_file = TmpFileGuard(tokenizer_download_tmp_dir(tmp_dir, path).await.join(format!("{}.tmp", Uuid::new_v4())));
+    let tmp_path = tmp_file.0.as_path();
     
-    // Track the last error message
-    let mut last_error = String::from("");
-    for i in 0..15 {
+    // Track the last error
+    let mut last_error = TokenizerError::Download(String::from("no attempts were made"));
+    for i in 0..policy.max_attempts {
         if i != 0 {
-            tokio::time::sleep(Duration::from_millis(200)).await;
-        }
-        let res = download_tokenizer_file(http_client, http_path, tokenizer_api_token, tmp_path).await;
-        if let Err(err_msg) = res {
-            last_error = format!("failed to download tokenizer: {}", err_msg);
-            tracing::error!("{last_error}");
-            continue;
+            cancellable(cancel, tokio::time::sleep(policy.backoff(i))).await?;
         }
+        let download = download_tokenizer_file(http_client, http_path, tokenizer_api_token, tmp_path, path, expected_sha256, progress, policy);
+        let etag = match cancellable(cancel, download).await? {
+            Ok(etag) => etag,
+            Err(err @ (TokenizerError::HtmlResponse(_) | TokenizerError::TooLarge(_))) => {
+                let _ = tokio::fs::remove_file(tmp_path).await;
+                return Err(err);
+            }
+            Err(err) => {
+                last_error = err;
+                tracing::error!("{last_error}");
+                continue;
+            }
+        };
 
         let parent = path.parent();
         if parent.is_none() {
-            last_error = String::from("failed to download tokenizer: parent is not set");
+            last_error = TokenizerError::Io(String::from("tokenizer path has no parent"));
             tracing::error!("{last_error}");
             continue;
         }
 
         let res = tokio::fs::create_dir_all(parent.unwrap()).await;
         if let Err(err_msg) = res {
-            last_error = format!("failed to create parent dir: {}", err_msg);
+            last_error = TokenizerError::Io(format!("failed to create parent dir: {}", err_msg));
+            tracing::error!("{last_error}");
+            continue;
+        }
+
+        match decompress_tokenizer_file(tmp_path, policy.max_tokenizer_bytes) {
+            Ok(_) => {}
+            Err(err @ TokenizerError::TooLarge(_)) => return Err(err),
+            Err(err) => {
+                last_error = err;
+                tracing::error!("{last_error}");
+                let _ = tokio::fs::remove_file(tmp_path).await;
+                continue;
+            }
+        }
+
+        if let Err(err) = check_json_file(tmp_path) {
+            last_error = err;
             tracing::error!("{last_error}");
+            let _ = tokio::fs::remove_file(tmp_path).await;
+            let _ = tokio::fs::remove_file(etag_path(path)).await;
             continue;
         }
 
-        if !check_json_file(tmp_path) {
-            last_error = String::from("failed to download tokenizer: file is not a tokenizer");
+        if let Err(err) = check_sha256(tmp_path, expected_sha256).await {
+            last_error = err;
             tracing::error!("{last_error}");
+            let _ = tokio::fs::remove_file(tmp_path).await;
+            let _ = tokio::fs::remove_file(etag_path(path)).await;
             continue;
         }
 
-        match tokio::fs::copy(tmp_path, path).await {
-            Ok(_) => {
+        match move_tokenizer_file(tmp_path, path).await {
+            Ok(()) => {
                 tracing::info!("moved tokenizer to {}", path.display());
-                return Ok(());
+                match etag {
+                    Some(etag) => { let _ = tokio::fs::write(etag_path(path), etag).await; }
+                    None => { let _ = tokio::fs::remove_file(etag_path(path)).await; }
+                }
+                return Ok(true);
             },
             Err(e) => { 
-                last_error = format!("failed to copy tokenizer file: {}", e);
+                last_error = TokenizerError::Io(format!("failed to move tokenizer file: {}", e));
                 tracing::error!("{last_error}");
                 continue; 
             }
@@ -131,96 +682,3871 @@ async fn try_download_tokenizer_file_and_open(
     Err(last_error)
 }
 
-pub async fn cached_tokenizer(
-    global_context: Arc<ARwLock<GlobalContext>>,
-    model_rec: &BaseModelRecord,
-) -> Result<Option<Arc<Tokenizer>>, String> {
-    let model_id = strip_model_from_finetune(&model_rec.id);
-    let tokenizer_download_lock: Arc<AMutex<bool>> = global_context.read().await.tokenizer_download_lock.clone();
-    let _tokenizer_download_locked = tokenizer_download_lock.lock().await;
+// the shared tokenizer counts whole texts, so `model_max_length` from a sibling tokenizer_config.json is not applied either
+fn without_truncation_and_padding(mut tokenizer: Tokenizer) -> Tokenizer {
+    let _ = tokenizer.with_truncation(None);
+    tokenizer.with_padding(None);
+    tokenizer
+}
 
-    let (client2, cache_dir, tokenizer_in_gcx, hf_tokenizer_template) = {
-        let cx_locked = global_context.read().await;
-        let template = cx_locked.caps.clone().map(|caps| caps.hf_tokenizer_template.clone())
-            .unwrap_or_else(default_hf_tokenizer_template);
-        (cx_locked.http_client.clone(), cx_locked.cache_dir.clone(), cx_locked.tokenizer_map.clone().get(&model_id).cloned(), template)
-    };
+fn read_tokenizer_config(tokenizer_path: &Path) -> Option<serde_json::Value> {
+    let config_path = tokenizer_path.with_file_name("tokenizer_config.json");
+    serde_json::from_slice(&std::fs::read(&config_path).ok()?)
+        .map_err(|e| tracing::warn!("ignoring {}: {}", config_path.display(), e))
+        .ok()
+}
+
+/// The Jinja `chat_template` from a `tokenizer_config.json` next to the tokenizer file. Some models ship
+/// a list of named templates instead of one string, then the "default" one is used.
+pub fn chat_template_from_tokenizer_config(tokenizer_path: &Path) -> Option<String> {
+    let config = read_tokenizer_config(tokenizer_path)?;
+    match config.get("chat_template")? {
+        serde_json::Value::String(template) => Some(template.clone()),
+        serde_json::Value::Array(templates) => templates.iter()
+            .find(|t| t.get("name").and_then(|name| name.as_str()) == Some("default"))
+            .and_then(|t| t.get("template")?.as_str().map(|template| template.to_string())),
+        _ => None,
+    }
+}
+
+/// Renders `messages` with a HuggingFace chat template, to count the tokens the model actually sees.
+/// Templates only get `role` and the text of `content` of each message.
+pub fn render_chat(chat_template: &str, messages: &[ChatMessage], add_generation_prompt: bool) -> Result<String, String> {
+    let mut env = minijinja::Environment::new();
+    // same settings as transformers, templates are written for them
+    env.set_trim_blocks(true);
+    env.set_lstrip_blocks(true);
+    env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+    env.add_function("raise_exception", |message: String| -> Result<String, minijinja::Error> {
+        Err(minijinja::Error::new(minijinja::ErrorKind::InvalidOperation, message))
+    });
+    env.add_template("chat", chat_template)
+        .map_err(|e| format!("invalid chat template: {e}"))?;
+    let messages: Vec<serde_json::Value> = messages.iter()
+        .map(|message| serde_json::json!({"role": message.role, "content": message.content.content_text_only()}))
+        .collect();
+    env.get_template("chat")
+        .and_then(|template| template.render(minijinja::context! { messages, add_generation_prompt }))
+        .map_err(|e| format!("failed to render chat template: {e}"))
+}
+
+fn check_truncation_params(params: &TruncationParams) -> Result<(), String> {
+    if params.max_length == 0 {
+        return Err("truncation max_length must be positive".to_string());
+    }
+    if params.stride >= params.max_length {
+        return Err(format!("truncation stride {} must be less than max_length {}", params.stride, params.max_length));
+    }
+    Ok(())
+}
+
+fn check_padding_params(params: &PaddingParams) -> Result<(), String> {
+    if matches!(params.strategy, PaddingStrategy::Fixed(0)) {
+        return Err("padding to a fixed length of 0".to_string());
+    }
+    if params.pad_to_multiple_of == Some(0) {
+        return Err("padding to a multiple of 0".to_string());
+    }
+    Ok(())
+}
+
+/// `Tokenizer::with_truncation` that rejects `max_length == 0`, which would silently drop every token
+pub fn set_truncation(tokenizer: &mut Tokenizer, params: Option<TruncationParams>) -> Result<(), String> {
+    if let Some(params) = &params {
+        check_truncation_params(params)?;
+    }
+    tokenizer.with_truncation(params).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// `Tokenizer::with_padding` that rejects padding to a fixed length of 0 or to a multiple of 0
+pub fn set_padding(tokenizer: &mut Tokenizer, params: Option<PaddingParams>) -> Result<(), String> {
+    if let Some(params) = &params {
+        check_padding_params(params)?;
+    }
+    tokenizer.with_padding(params);
+    Ok(())
+}
+
+/// Encodes as a copy of `tokenizer` with these truncation and padding would, without making the copy:
+/// the encoding is truncated, post-processed and padded the way `Tokenizer::post_process` does it.
+/// The tokenizer's own truncation and padding are not used
+pub fn encode_with_params(
+    tokenizer: &Tokenizer,
+    text: &str,
+    add_special: bool,
+    truncation: Option<&TruncationParams>,
+    padding: Option<&PaddingParams>,
+) -> Result<Encoding, String> {
+    encode_pretokenized(tokenizer, text, add_special, truncation, padding, None)
+}
+
+/// `encode_with_params` that gives up once `deadline` passes, checked before each pre-token goes to the model
+fn encode_pretokenized(
+    tokenizer: &Tokenizer,
+    text: &str,
+    add_special: bool,
+    truncation: Option<&TruncationParams>,
+    padding: Option<&PaddingParams>,
+    deadline: Option<Instant>,
+) -> Result<Encoding, String> {
+    truncation.map(check_truncation_params).transpose()?;
+    padding.map(check_padding_params).transpose()?;
+    let mut encoding = tokenize_text(tokenizer, text, true, deadline)?
+        .into_encoding(None, 0, OffsetType::Byte)
+        .map_err(|e| format!("Encoding error: {e}"))?;
 
-    if let Some(tokenizer) = tokenizer_in_gcx {
-        return Ok(tokenizer)
+    if let Some(truncation) = truncation {
+        let special_count = match tokenizer.get_post_processor() {
+            Some(post_processor) if add_special => post_processor.added_tokens(false),
+            _ => 0,
+        };
+        let params = TruncationParams { max_length: truncation.max_length.saturating_sub(special_count), ..truncation.clone() };
+        encoding = truncate_encodings(encoding, None, &params).map_err(|e| format!("Encoding error: {e}"))?.0;
     }
+    let mut encoding = match tokenizer.get_post_processor() {
+        Some(post_processor) => post_processor.process(encoding, None, add_special),
+        None => <dyn PostProcessor>::default_process(vec![encoding], add_special).map(|mut encodings| encodings.remove(0)),
+    }.map_err(|e| format!("Encoding error: {e}"))?;
+    if let Some(padding) = padding {
+        pad_encodings(std::slice::from_mut(&mut encoding), padding).map_err(|e| format!("Encoding error: {e}"))?;
+    }
+    Ok(encoding)
+}
+
+/// Normalization, pre-tokenization and the model, the part of encoding every caller here shares.
+/// With `split_added` the added tokens are cut out of `text` first, otherwise their text is tokenized like any other.
+/// `deadline` is checked before each pre-token goes to the model
+fn tokenize_text(
+    tokenizer: &Tokenizer,
+    text: &str,
+    split_added: bool,
+    deadline: Option<Instant>,
+) -> Result<PreTokenizedString, String> {
+    tokenize_text_until(tokenizer, text, split_added, || deadline.is_some_and(|deadline| Instant::now() >= deadline))
+}
 
-    let (mut tok_file_path, tok_url) = match &model_rec.tokenizer {
-        empty_tok if empty_tok.is_empty() => return Err(format!("failed to load tokenizer: empty tokenizer for {model_id}")),
-        fake_tok if fake_tok.starts_with("fake") => return Ok(None),
-        hf_tok if hf_tok.starts_with("hf://") => {
-            let hf_model = hf_tok.strip_prefix("hf://").unwrap();
-            let url = hf_tokenizer_template.replace("$HF_MODEL", hf_model);
-            (PathBuf::new(), url)
+/// `tokenize_text` that asks `is_late` before each pre-token and stops at the first one it's late for
+fn tokenize_text_until(
+    tokenizer: &Tokenizer,
+    text: &str,
+    split_added: bool,
+    is_late: impl Fn() -> bool,
+) -> Result<PreTokenizedString, String> {
+    let mut pretokenized = if split_added {
+        tokenizer.get_added_vocabulary().extract_and_normalize(tokenizer.get_normalizer(), text)
+    } else {
+        let mut normalized = NormalizedString::from(text);
+        if let Some(normalizer) = tokenizer.get_normalizer() {
+            normalizer.normalize(&mut normalized).map_err(|e| format!("Encoding error: {e}"))?;
         }
-        http_tok if http_tok.starts_with("http://") || http_tok.starts_with("https://") => {
-            (PathBuf::new(), http_tok.to_string())
+        PreTokenizedString::from(normalized)
+    };
+    if let Some(pre_tokenizer) = tokenizer.get_pre_tokenizer() {
+        pre_tokenizer.pre_tokenize(&mut pretokenized).map_err(|e| format!("Encoding error: {e}"))?;
+    }
+    let timed_out = Cell::new(false);
+    pretokenized.tokenize(|normalized| {
+        if is_late() {
+            timed_out.set(true);
+            return Err("deadline passed".into());
         }
-        file_tok => {
-            let file = if file_tok.starts_with("file://") {
-                url::Url::parse(file_tok)
-                    .and_then(|url| url.to_file_path().map_err(|_| url::ParseError::EmptyHost))
-                    .map_err_with_prefix(format!("Invalid path URL {file_tok}:"))?
-            } else {
-                canonical_path(file_tok)
-            };
-            (canonical_path(file.to_string_lossy()), "".to_string())
+        tokenizer.get_model().tokenize(normalized.get())
+    }).map_err(|e| match timed_out.get() {
+        true => format!("Encoding timeout: {} bytes were not encoded before the deadline", text.len()),
+        false => format!("Encoding error: {e}"),
+    })?;
+    Ok(pretokenized)
+}
+
+pub const TOKENIZER_VARIANTS_CAPACITY: usize = 8;
+
+/// Copies of a shared tokenizer with other truncation and padding. Cloning a big vocab is expensive,
+/// so each distinct configuration is cloned once and reused; the least recently used one is dropped when the map is full.
+pub struct TokenizerVariants {
+    base: Arc<Tokenizer>,
+    variants: StdMutex<VariantMap>,
+}
+
+#[derive(Default)]
+struct VariantMap {
+    map: HashMap<String, Arc<Tokenizer>>,
+    in_used_order: Vec<String>,  // least recently used first
+}
+
+impl VariantMap {
+    fn touch(&mut self, key: &str) {
+        self.in_used_order.retain(|k| k != key);
+        self.in_used_order.push(key.to_string());
+    }
+}
+
+impl TokenizerVariants {
+    pub fn new(base: Arc<Tokenizer>) -> Self {
+        TokenizerVariants { base, variants: StdMutex::new(VariantMap::default()) }
+    }
+
+    pub fn with_truncation(&self, params: Option<TruncationParams>) -> Result<Arc<Tokenizer>, String> {
+        self.with_truncation_and_padding(params, self.base.get_padding().cloned())
+    }
+
+    pub fn with_padding(&self, params: Option<PaddingParams>) -> Result<Arc<Tokenizer>, String> {
+        self.with_truncation_and_padding(self.base.get_truncation().cloned(), params)
+    }
+
+    pub fn with_truncation_and_padding(
+        &self,
+        truncation: Option<TruncationParams>,
+        padding: Option<PaddingParams>,
+    ) -> Result<Arc<Tokenizer>, String> {
+        // the params don't implement Hash, their json does the job
+        let key = serde_json::to_string(&(&truncation, &padding)).map_err(|e| e.to_string())?;
+
+        {
+            let mut variants = self.variants.lock().unwrap();
+            if let Some(variant) = variants.map.get(&key).cloned() {
+                variants.touch(&key);
+                return Ok(variant);
+            }
+        }
+        let mut tokenizer = (*self.base).clone();
+        set_truncation(&mut tokenizer, truncation)?;
+        set_padding(&mut tokenizer, padding)?;
+        let mut variants = self.variants.lock().unwrap();
+        let variant = variants.map.entry(key.clone()).or_insert_with(|| Arc::new(tokenizer)).clone();
+        variants.touch(&key);
+        while variants.in_used_order.len() > TOKENIZER_VARIANTS_CAPACITY {
+            let evicted = variants.in_used_order.remove(0);
+            variants.map.remove(&evicted);
         }
+        Ok(variant)
+    }
+}
+
+/// Loads tokenizer.json contents from memory, e.g. embedded in the binary, without using the cache directory
+pub fn tokenizer_from_bytes(bytes: &[u8]) -> Result<Tokenizer, TokenizerError> {
+    let tokenizer = Tokenizer::from_bytes(bytes)
+        .map_err(|e| TokenizerError::Parse(e.to_string()))?;
+    Ok(without_truncation_and_padding(tokenizer))
+}
+
+/// Fills `$HF_MODEL`, `$HF_REVISION` and `$HF_FILENAME` in a caps `hf_tokenizer_template`
+fn hf_tokenizer_url(template: &str, hf_model: &str, revision: Option<&str>, filename: Option<&str>) -> String {
+    template
+        .replace("$HF_MODEL", hf_model)
+        .replace("$HF_REVISION", revision.unwrap_or("main"))
+        .replace("$HF_FILENAME", filename.unwrap_or("tokenizer.json"))
+}
+
+/// The HuggingFace hub cache: `HF_HUB_CACHE`, or `HF_HOME/hub`. `lookup_env` reads the variables, `std::env::var` in the engine
+pub fn hf_hub_cache_dir(lookup_env: impl Fn(&str) -> Option<String>) -> Option<PathBuf> {
+    let non_empty = |name: &str| lookup_env(name).filter(|value| !value.trim().is_empty());
+    non_empty("HF_HUB_CACHE").map(PathBuf::from)
+        .or_else(|| non_empty("HF_HOME").map(|hf_home| PathBuf::from(hf_home).join("hub")))
+}
+
+/// A file of `repo` that HuggingFace tools have already downloaded to the hub cache,
+/// `models--<org>--<name>/snapshots/<commit>/<filename>`. A branch or tag `revision` goes through `refs/`,
+/// names that could step out of the repo directory are never looked up
+fn hf_hub_cached_file(hub_cache: &Path, repo: &str, revision: Option<&str>, filename: Option<&str>) -> Option<PathBuf> {
+    let repo_dir = hub_cache.join(format!("models--{}", repo.replace('/', "--")));
+    let revision = revision.unwrap_or("main");
+    let filename = filename.unwrap_or("tokenizer.json");
+    if revision.is_empty() || revision.contains("..") || revision.contains(['/', '\\']) || filename.split(['/', '\\']).any(|part| part == "..") {
+        return None;
+    }
+    let commit = match std::fs::read_to_string(repo_dir.join("refs").join(revision)) {
+        Ok(commit) => commit.trim().to_string(),
+        // a commit hash names the snapshot directly
+        Err(_) => revision.to_string(),
     };
+    let is_commit_hash = commit.len() == 40 && commit.chars().all(|c| c.is_ascii_hexdigit());
+    if !is_commit_hash {
+        return None;
+    }
+    let path = repo_dir.join("snapshots").join(commit).join(filename);
+    path.is_file().then_some(path)
+}
+
+/// Where a downloaded tokenizer of `model_id` is kept, `cache_dir/tokenizers/<sanitized model id>/tokenizer.json`
+fn sanitize_model_id(model_id: &str) -> String {
+    model_id.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect()
+}
 
-    if tok_file_path.as_os_str().is_empty() {
-        let tokenizer_cache_dir = std::path::PathBuf::from(cache_dir).join("tokenizers");
-        let sanitized_model_id = model_id.chars()
-            .map(|c| if c.is_alphanumeric() { c } else { '_' })
-            .collect::<String>();
-        
-        tok_file_path = tokenizer_cache_dir.join(&sanitized_model_id).join("tokenizer.json");
+fn tokenizer_cache_path(cache_dir: &Path, model_id: &str) -> PathBuf {
+    cache_dir.join("tokenizers").join(sanitize_model_id(model_id)).join("tokenizer.json")
+}
+
+/// For a model with several remote tokenizers, each one is kept apart in the model's directory, under a hash of its url
+fn tokenizer_candidate_cache_path(cache_dir: &Path, model_id: &str, url: &str) -> PathBuf {
+    let url_hash = format!("{:x}", Sha256::digest(url.as_bytes()));
+    tokenizer_cache_path(cache_dir, model_id).with_file_name(&url_hash[..16]).join("tokenizer.json")
+}
 
-        try_download_tokenizer_file_and_open(&client2, &tok_url, &model_rec.tokenizer_api_key, &tok_file_path).await?;
+async fn invalidate_cached_tokenizer(
+    tokenizer_map: &AMutex<TokenizerCache>,
+    cache_dir: &Path,
+    model_id: &str,
+) -> Result<(), TokenizerError> {
+    tokenizer_map.lock().await.remove(model_id);
+    let tokenizer_path = tokenizer_cache_path(cache_dir, model_id);
+    let Some(model_dir) = tokenizer_path.parent() else {
+        return Ok(());
+    };
+    match tokio::fs::remove_dir_all(model_dir).await {
+        Ok(()) => {
+            tracing::info!("removed cached tokenizer {}", model_dir.display());
+            Ok(())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(TokenizerError::Io(format!("failed to remove {}: {}", model_dir.display(), e))),
     }
-    
-    tracing::info!("loading tokenizer \"{}\"", tok_file_path.display());
-    let mut tokenizer = Tokenizer::from_file(tok_file_path)
-        .map_err(|e| format!("failed to load tokenizer: {}", e))?;
-    let _ = tokenizer.with_truncation(None);
-    tokenizer.with_padding(None);
-    let arc = Some(Arc::new(tokenizer));
+}
 
-    global_context.write().await.tokenizer_map.insert(model_id, arc.clone());
-    Ok(arc)
+/// A downloaded `tokenizer.json` under `cache_dir/tokenizers`, `model_id` is the sanitized directory name.
+/// A model with several remote candidates has an entry for each of them
+#[derive(Debug, Clone)]
+pub struct CachedTokenizerEntry {
+    pub model_id: String,
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
 }
 
-/// Estimate as length / 3.5, since 3 is reasonable estimate for code, and 4 for natural language
-fn estimate_tokens(text: &str) -> usize {  1 + text.len() * 2 / 7 }
+pub fn list_cached_tokenizers(cache_dir: &Path) -> Vec<CachedTokenizerEntry> {
+    let Ok(dir) = std::fs::read_dir(cache_dir.join("tokenizers")) else {
+        return vec![];
+    };
+    let mut entries: Vec<CachedTokenizerEntry> = dir.flatten()
+        .flat_map(|model_dir| {
+            let model_id = model_dir.file_name().to_string_lossy().to_string();
+            cached_tokenizer_files(&model_dir.path()).into_iter().map(move |(path, metadata)| CachedTokenizerEntry {
+                model_id: model_id.clone(),
+                path,
+                size: metadata.len(),
+                modified: metadata.modified().ok(),
+            })
+        })
+        .collect();
+    entries.sort_by(|a, b| (&a.model_id, &a.path).cmp(&(&b.model_id, &b.path)));
+    entries
+}
 
-pub fn count_text_tokens(
-    tokenizer: Option<Arc<Tokenizer>>,
-    text: &str,
-) -> Result<usize, String> {
-    match tokenizer {
-        Some(tokenizer) => {
-            match tokenizer.encode_fast(text, false) {
-                Ok(tokens) => Ok(tokens.len()),
-                Err(e) => Err(format!("Encoding error: {e}")),
-            }
+/// `<model_dir>/tokenizer.json` and the `<model_dir>/<url hash>/tokenizer.json` of each candidate
+fn cached_tokenizer_files(model_dir: &Path) -> Vec<(PathBuf, std::fs::Metadata)> {
+    let mut candidate_dirs: Vec<PathBuf> = std::fs::read_dir(model_dir).into_iter().flatten().flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect();
+    candidate_dirs.sort();
+    std::iter::once(model_dir.to_path_buf()).chain(candidate_dirs)
+        .map(|dir| dir.join("tokenizer.json"))
+        .filter_map(|path| std::fs::metadata(&path).ok().filter(|m| m.is_file()).map(|m| (path, m)))
+        .collect()
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let Ok(dir) = std::fs::read_dir(path) else {
+        return 0;
+    };
+    dir.flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(m) if m.is_dir() => dir_size(&entry.path()),
+            Ok(m) => m.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Total bytes of everything under `cache_dir/tokenizers`
+pub fn tokenizer_cache_size(cache_dir: &Path) -> u64 {
+    dir_size(&cache_dir.join("tokenizers"))
+}
+
+/// Deletes the least recently modified tokenizer directories until the cache fits into `max_bytes`, a directory
+/// is as recent as the newest `tokenizer.json` in it, candidates included. Directories named in `in_use`
+/// (sanitized model ids) are never deleted. Returns the number of bytes freed.
+pub async fn prune_tokenizer_cache(cache_dir: &Path, max_bytes: u64, in_use: &HashSet<String>) -> u64 {
+    let Ok(dir) = std::fs::read_dir(cache_dir.join("tokenizers")) else {
+        return 0;
+    };
+    let mut model_dirs: Vec<(PathBuf, u64, SystemTime)> = dir.flatten()
+        .filter(|entry| entry.path().is_dir() && !in_use.contains(entry.file_name().to_string_lossy().as_ref()))
+        .map(|entry| {
+            let path = entry.path();
+            let modified = cached_tokenizer_files(&path).iter()
+                .filter_map(|(_, metadata)| metadata.modified().ok())
+                .max()
+                .or_else(|| entry.metadata().and_then(|m| m.modified()).ok())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            let size = dir_size(&path);
+            (path, size, modified)
+        })
+        .collect();
+    model_dirs.sort_by_key(|(_, _, modified)| *modified);
+
+    let mut total = tokenizer_cache_size(cache_dir);
+    let mut freed = 0;
+    for (path, size, _) in model_dirs {
+        if total <= max_bytes {
+            break;
         }
-        None => {
-            Ok(estimate_tokens(text))
+        match tokio::fs::remove_dir_all(&path).await {
+            Ok(()) => {
+                tracing::info!("pruned cached tokenizer {} ({} bytes)", path.display(), size);
+                total = total.saturating_sub(size);
+                freed += size;
+            }
+            Err(e) => tracing::warn!("failed to prune cached tokenizer {}: {}", path.display(), e),
         }
     }
+    freed
 }
 
-pub fn count_text_tokens_with_fallback(
-    tokenizer: Option<Arc<Tokenizer>>,
+/// Reads environment variables for a `TokenizerRegistry`: `std::env::var` by default, tests give it their own
+#[derive(Clone)]
+pub struct EnvLookup(Arc<EnvLookupFn>);
+
+type EnvLookupFn = dyn Fn(&str) -> Option<String> + Send + Sync;
+
+impl EnvLookup {
+    fn get(&self, name: &str) -> Option<String> {
+        (self.0)(name)
+    }
+}
+
+impl Default for EnvLookup {
+    fn default() -> Self {
+        EnvLookup(Arc::new(|name| std::env::var(name).ok()))
+    }
+}
+
+impl fmt::Debug for EnvLookup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EnvLookup")
+    }
+}
+
+/// Where a `TokenizerRegistry` keeps and gets tokenizer files, fixed when the registry is created
+#[derive(Debug, Clone, Default)]
+pub struct TokenizerRegistryConfig {
+    pub cache_dir: PathBuf,
+    /// Accept invalid certificates, like the rest of the engine with `--insecure`
+    pub insecure: bool,
+    /// HuggingFace hub cache, see `hf_hub_cache_dir`. HuggingFace tokenizers found there are read in place,
+    /// the engine never writes or deletes anything in it
+    pub hf_hub_cache: Option<PathBuf>,
+    pub offline: bool,
+    pub download_policy: TokenizerDownloadPolicy,
+    pub proxy_url: Option<String>,
+    pub ca_cert_path: Option<PathBuf>,
+    pub tmp_dir: Option<PathBuf>,
+    pub manifest: Option<Arc<TokenizerManifest>>,
+    /// Where `REFACT_TOKENIZER_OVERRIDE_<MODEL_ID>` and `HF_TOKEN` are read from
+    pub env: EnvLookup,
+}
+
+/// Loaded tokenizers with their download locks, metrics and download settings, everything `cached_tokenizer`
+/// needs except the caps template. The engine keeps one in `GlobalContext`, tests can build their own
+pub struct TokenizerRegistry {
+    config: TokenizerRegistryConfig,
+    tokenizer_map: AMutex<TokenizerCache>,
+    /// Which of the model's `tokenizer_candidates` its tokenizer was loaded from
+    loaded_from: StdMutex<HashMap<String, String>>,
+    download_locks: TokenizerDownloadLocks,
+    metrics: TokenizerMetrics,
+    http_client: OnceLock<reqwest::Client>,
+}
+
+impl TokenizerRegistry {
+    pub fn new(config: TokenizerRegistryConfig, capacity: usize) -> Self {
+        TokenizerRegistry {
+            config,
+            tokenizer_map: AMutex::new(TokenizerCache::new(capacity)),
+            loaded_from: StdMutex::new(HashMap::new()),
+            download_locks: TokenizerDownloadLocks::default(),
+            metrics: TokenizerMetrics::default(),
+            http_client: OnceLock::new(),
+        }
+    }
+
+    pub fn metrics(&self) -> TokenizerMetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    pub async fn get(
+        &self,
+        model_rec: &BaseModelRecord,
+        hf_tokenizer_template: &str,
+    ) -> Result<Option<Arc<Tokenizer>>, TokenizerError> {
+        self.get_with_progress(model_rec, hf_tokenizer_template, None, None).await
+    }
+
+    pub async fn get_with_progress(
+        &self,
+        model_rec: &BaseModelRecord,
+        hf_tokenizer_template: &str,
+        progress: Option<&TokenizerDownloadProgress>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<Option<Arc<Tokenizer>>, TokenizerError> {
+        let model_id = strip_model_from_finetune(&model_rec.id);
+        let model_rec = self.effective_model_record(model_rec);
+        let http_client = self.tokenizer_http_client()?;
+        let cx = TokenizerLoadContext {
+            config: &self.config, http_client: &http_client, hf_tokenizer_template, metrics: &self.metrics, progress, cancel,
+        };
+        get_or_load_tokenizer(&self.tokenizer_map, &self.download_locks, &self.metrics, &model_id, || async {
+            let (tokenizer, source) = load_tokenizer_and_source(&cx, &model_rec, &model_id).await?;
+            self.loaded_from.lock().unwrap().insert(model_id.clone(), source);
+            Ok(tokenizer)
+        }).await
+    }
+
+    /// The plan of the candidate the model's tokenizer was loaded from, of the first candidate if it's not loaded
+    fn loaded_tokenizer_plan(&self, model_rec: &BaseModelRecord, hf_tokenizer_template: &str) -> Result<TokenizerPlan, TokenizerError> {
+        let model_id = strip_model_from_finetune(&model_rec.id);
+        let model_rec = self.effective_model_record(model_rec);
+        let candidates = tokenizer_candidates(&model_rec);
+        let loaded_from = self.loaded_from.lock().unwrap().get(&model_id).cloned();
+        let tokenizer = loaded_from.as_deref()
+            .filter(|source| candidates.contains(source))
+            .or(candidates.first().copied())
+            .unwrap_or_default();
+        resolve_candidate_plan(&model_rec, tokenizer, candidates.len() > 1, &self.config.cache_dir, self.config.hf_hub_cache.as_deref(), hf_tokenizer_template)
+    }
+
+    /// The model record with the env var override or the manifest entry applied to its `tokenizer`
+    fn effective_model_record<'a>(&self, model_rec: &'a BaseModelRecord) -> Cow<'a, BaseModelRecord> {
+        match with_tokenizer_override(model_rec, |name| self.config.env.get(name)) {
+            Cow::Borrowed(model_rec) => with_manifest_tokenizer(model_rec, self.config.manifest.as_deref()),
+            Cow::Owned(model_rec) => Cow::Owned(with_manifest_tokenizer(&model_rec, self.config.manifest.as_deref()).into_owned()),
+        }
+    }
+
+    pub async fn invalidate(&self, model_id: &str) -> Result<(), TokenizerError> {
+        let model_id = strip_model_from_finetune(model_id);
+        self.loaded_from.lock().unwrap().remove(&model_id);
+        invalidate_cached_tokenizer(&self.tokenizer_map, &self.config.cache_dir, &model_id).await
+    }
+
+    /// `prune_tokenizer_cache` that keeps the tokenizers currently loaded
+    pub async fn prune(&self, max_bytes: u64) -> u64 {
+        let in_use: HashSet<String> = self.tokenizer_map.lock().await.model_ids()
+            .map(|model_id| sanitize_model_id(model_id))
+            .collect();
+        prune_tokenizer_cache(&self.config.cache_dir, max_bytes, &in_use).await
+    }
+
+    /// Tokenizer downloads have their own connect timeout and maybe a proxy or CA, so they use a client
+    /// of their own, built once and reused
+    fn tokenizer_http_client(&self) -> Result<reqwest::Client, TokenizerError> {
+        if let Some(client) = self.http_client.get() {
+            return Ok(client.clone());
+        }
+        let client = build_tokenizer_http_client(
+            self.config.proxy_url.as_deref(), self.config.ca_cert_path.as_deref(), self.config.download_policy.connect_timeout, self.config.insecure,
+        )?;
+        Ok(self.http_client.get_or_init(|| client).clone())
+    }
+}
+
+pub async fn cached_tokenizer(
+    global_context: Arc<ARwLock<GlobalContext>>,
+    model_rec: &BaseModelRecord,
+) -> Result<Option<Arc<Tokenizer>>, TokenizerError> {
+    cached_tokenizer_with_progress(global_context, model_rec, None, None).await
+}
+
+/// Same as `cached_tokenizer`, reports download progress if the tokenizer is not in the cache yet,
+/// and stops retrying the download with `TokenizerError::Cancelled` once `cancel` fires
+pub async fn cached_tokenizer_with_progress(
+    global_context: Arc<ARwLock<GlobalContext>>,
+    model_rec: &BaseModelRecord,
+    progress: Option<TokenizerDownloadProgress>,
+    cancel: Option<CancellationToken>,
+) -> Result<Option<Arc<Tokenizer>>, TokenizerError> {
+    let (registry, hf_tokenizer_template) = registry_and_template(&global_context).await;
+    registry.get_with_progress(model_rec, &hf_tokenizer_template, progress.as_ref(), cancel.as_ref()).await
+}
+
+async fn registry_and_template(global_context: &Arc<ARwLock<GlobalContext>>) -> (Arc<TokenizerRegistry>, String) {
+    let cx_locked = global_context.read().await;
+    let template = cx_locked.caps.clone().map(|caps| caps.hf_tokenizer_template.clone())
+        .unwrap_or_else(default_hf_tokenizer_template);
+    (cx_locked.tokenizer_registry.clone(), template)
+}
+
+fn build_tokenizer_http_client(
+    proxy_url: Option<&str>,
+    ca_cert_path: Option<&Path>,
+    connect_timeout: Duration,
+    insecure: bool,
+) -> Result<reqwest::Client, TokenizerError> {
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(connect_timeout)
+        .danger_accept_invalid_certs(insecure);
+    if let Some(proxy_url) = proxy_url {
+        let proxy = reqwest::Proxy::all(proxy_url)
+            .map_err(|e| TokenizerError::Download(format!("invalid tokenizer proxy {proxy_url}: {e}")))?;
+        builder = builder.proxy(proxy);
+    }
+    if let Some(ca_cert_path) = ca_cert_path {
+        let pem = std::fs::read(ca_cert_path)
+            .map_err(|e| TokenizerError::Io(format!("failed to read {}: {}", ca_cert_path.display(), e)))?;
+        let certs = reqwest::Certificate::from_pem_bundle(&pem)
+            .map_err(|e| TokenizerError::Parse(format!("invalid certificate {}: {}", ca_cert_path.display(), e)))?;
+        if certs.is_empty() {
+            return Err(TokenizerError::Parse(format!("no certificates in {}", ca_cert_path.display())));
+        }
+        for cert in certs {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    builder.build()
+        .map_err(|e| TokenizerError::Download(format!("failed to build tokenizer http client: {e}")))
+}
+
+/// Result of `warm_tokenizers`, failed ids come with the error message
+#[derive(Debug, Default)]
+pub struct TokenizerWarmupSummary {
+    pub succeeded: Vec<String>,
+    pub failed: Vec<(String, String)>,
+}
+
+fn model_record_for_tokenizer(caps: &CodeAssistantCaps, model_id: &str) -> Result<BaseModelRecord, String> {
+    if let Ok(model_rec) = resolve_model(&caps.chat_models, model_id) {
+        return Ok(model_rec.base.clone());
+    }
+    if let Ok(model_rec) = resolve_model(&caps.completion_models, model_id) {
+        return Ok(model_rec.base.clone());
+    }
+    if caps.embedding_model.base.id == model_id {
+        return Ok(caps.embedding_model.base.clone());
+    }
+    Err(format!("model {model_id} is not in caps"))
+}
+
+/// Loads tokenizers of the given models concurrently, so the first real request doesn't wait for a download.
+/// Failures are logged and reported in the summary, they don't stop the other models
+pub async fn warm_tokenizers(
+    global_context: Arc<ARwLock<GlobalContext>>,
+    model_ids: &[String],
+) -> TokenizerWarmupSummary {
+    let mut summary = TokenizerWarmupSummary::default();
+    let caps = match try_load_caps_quickly_if_not_present(global_context.clone(), 0).await {
+        Ok(caps) => caps,
+        Err(e) => {
+            tracing::warn!("cannot warm tokenizers, caps are not loaded: {}", e.message);
+            summary.failed = model_ids.iter().map(|model_id| (model_id.clone(), e.message.clone())).collect();
+            return summary;
+        }
+    };
+    let results = futures::future::join_all(model_ids.iter().map(|model_id| {
+        let model_rec = model_record_for_tokenizer(&caps, model_id);
+        let gcx = global_context.clone();
+        async move {
+            let model_rec = model_rec?;
+            cached_tokenizer(gcx, &model_rec).await.map_err(|e| e.to_string())
+        }
+    })).await;
+    for (model_id, result) in model_ids.iter().zip(results) {
+        match result {
+            Ok(_) => summary.succeeded.push(model_id.clone()),
+            Err(e) => {
+                tracing::warn!("failed to warm tokenizer of {model_id}: {e}");
+                summary.failed.push((model_id.clone(), e));
+            }
+        }
+    }
+    tracing::info!("warmed {} tokenizers, {} failed", summary.succeeded.len(), summary.failed.len());
+    summary
+}
+
+/// Every model in caps once, chat models first
+fn configured_model_records(caps: &CodeAssistantCaps) -> Vec<BaseModelRecord> {
+    let mut seen = HashSet::new();
+    caps.chat_models.values().map(|model_rec| &model_rec.base)
+        .chain(caps.completion_models.values().map(|model_rec| &model_rec.base))
+        .chain(std::iter::once(&caps.embedding_model.base))
+        .filter(|model_rec| !model_rec.id.is_empty() && seen.insert(model_rec.id.clone()))
+        .cloned()
+        .collect()
+}
+
+/// Loads each model's tokenizer with `load` and describes it, models without a real tokenizer are
+/// reported as a "fake" backend with vocab size 0
+async fn check_tokenizers<F, Fut>(model_recs: Vec<BaseModelRecord>, load: F) -> Vec<(String, Result<TokenizerInfo, String>)>
+where
+    F: Fn(BaseModelRecord) -> Fut,
+    Fut: Future<Output = Result<Option<Arc<Tokenizer>>, TokenizerError>>,
+{
+    let results = futures::future::join_all(model_recs.iter().cloned().map(load)).await;
+    model_recs.into_iter().zip(results)
+        .map(|(model_rec, result)| {
+            let info = result
+                .map(|tokenizer| match tokenizer {
+                    Some(tokenizer) => describe(&tokenizer, &model_rec.tokenizer),
+                    None => TokenizerInfo { backend: "fake".to_string(), model_name: model_rec.tokenizer.clone(), vocab_size: 0 },
+                })
+                .map_err(|e| e.to_string());
+            (model_rec.id, info)
+        })
+        .collect()
+}
+
+/// For a readiness probe: loads the tokenizer of every configured model and reports each one, or why it failed
+pub async fn check_all_tokenizers(global_context: Arc<ARwLock<GlobalContext>>) -> Vec<(String, Result<TokenizerInfo, String>)> {
+    let caps = match try_load_caps_quickly_if_not_present(global_context.clone(), 0).await {
+        Ok(caps) => caps,
+        Err(e) => {
+            tracing::warn!("cannot check tokenizers, caps are not loaded: {}", e.message);
+            return vec![];
+        }
+    };
+    check_tokenizers(configured_model_records(&caps), |model_rec| {
+        let gcx = global_context.clone();
+        async move { cached_tokenizer(gcx, &model_rec).await }
+    }).await
+}
+
+/// Returns the cached tokenizer, or runs `load` holding the model's download lock.
+/// The cache is checked again once the lock is taken, so concurrent requests load a tokenizer once
+async fn get_or_load_tokenizer<F, Fut>(
+    tokenizer_map: &AMutex<TokenizerCache>,
+    download_locks: &TokenizerDownloadLocks,
+    metrics: &TokenizerMetrics,
+    model_id: &str,
+    load: F,
+) -> Result<Option<Arc<Tokenizer>>, TokenizerError>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<Option<Arc<Tokenizer>>, TokenizerError>>,
+{
+    if let Some(tokenizer) = tokenizer_map.lock().await.get(model_id) {
+        metrics.cache_hits.fetch_add(1, AtomicOrdering::Relaxed);
+        return Ok(tokenizer);
+    }
+    let download_lock = download_locks.lock_for(model_id);
+    let _download_locked = download_lock.lock().await;
+    // the request we waited for has probably loaded it already
+    if let Some(tokenizer) = tokenizer_map.lock().await.get(model_id) {
+        metrics.cache_hits.fetch_add(1, AtomicOrdering::Relaxed);
+        return Ok(tokenizer);
+    }
+    metrics.cache_misses.fetch_add(1, AtomicOrdering::Relaxed);
+
+    // `None` (fake tokenizers) is cached too, so those models don't come back here on every call
+    let tokenizer = load().await?;
+    tokenizer_map.lock().await.insert(model_id.to_string(), tokenizer.clone());
+    Ok(tokenizer)
+}
+
+/// The model's chat template, if its tokenizer comes with a `tokenizer_config.json` (only local tokenizer files do)
+pub async fn cached_chat_template(
+    global_context: Arc<ARwLock<GlobalContext>>,
+    model_rec: &BaseModelRecord,
+) -> Result<Option<String>, TokenizerError> {
+    if cached_tokenizer(global_context.clone(), model_rec).await?.is_none() {
+        return Ok(None);
+    }
+    let (registry, template) = registry_and_template(&global_context).await;
+    let plan = registry.loaded_tokenizer_plan(model_rec, &template)?;
+    Ok(plan.path.and_then(|path| chat_template_from_tokenizer_config(&path)))
+}
+
+pub const TOKENIZER_OVERRIDE_ENV_PREFIX: &str = "REFACT_TOKENIZER_OVERRIDE_";
+
+fn tokenizer_override_env_var(model_id: &str) -> String {
+    format!("{TOKENIZER_OVERRIDE_ENV_PREFIX}{}", sanitize_model_id(model_id).to_uppercase())
+}
+
+/// For debugging: `REFACT_TOKENIZER_OVERRIDE_<MODEL_ID>` (non-alphanumerics as `_`) replaces the model's tokenizer.
+/// `lookup_env` reads the variable, `TokenizerRegistryConfig::env` in the engine
+fn with_tokenizer_override(model_rec: &BaseModelRecord, lookup_env: impl Fn(&str) -> Option<String>) -> Cow<'_, BaseModelRecord> {
+    let env_var = tokenizer_override_env_var(&strip_model_from_finetune(&model_rec.id));
+    match lookup_env(&env_var) {
+        Some(tokenizer) if !tokenizer.trim().is_empty() => {
+            tracing::warn!("{env_var} is set, using tokenizer \"{tokenizer}\" instead of \"{}\"", model_rec.tokenizer);
+            Cow::Owned(BaseModelRecord { tokenizer: tokenizer.trim().to_string(), tokenizer_fallbacks: vec![], ..model_rec.clone() })
+        }
+        _ => Cow::Borrowed(model_rec),
+    }
+}
+
+/// Models that set `tokenizer` themselves keep it, the manifest only fills in the empty ones
+fn with_manifest_tokenizer<'a>(model_rec: &'a BaseModelRecord, manifest: Option<&TokenizerManifest>) -> Cow<'a, BaseModelRecord> {
+    if !model_rec.tokenizer.is_empty() {
+        return Cow::Borrowed(model_rec);
+    }
+    match manifest.and_then(|manifest| manifest.resolve(&strip_model_from_finetune(&model_rec.id))) {
+        Some(tokenizer) => Cow::Owned(BaseModelRecord { tokenizer: tokenizer.to_string(), ..model_rec.clone() }),
+        None => Cow::Borrowed(model_rec),
+    }
+}
+
+/// The `tokenizer` field of a model record: a HuggingFace repo, a URL or a local file (json, `gguf://`, or a dir with `vocab.json` + `merges.txt`).
+/// `hf://` goes through the caps template, `hf-repo:` always downloads from huggingface.co.
+/// `fake` means no tokenizer, `fake:chars_per_token=N` is a deterministic one that counts a token per N chars.
+/// A `data:` URI carries the whole tokenizer.json inline, as base64 or percent-encoded text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenizerSource {
+    Empty,
+    Fake,
+    FakeCharsPerToken(usize),
+    Data(Vec<u8>),
+    Hf(String),
+    HfRepo(String),
+    Http(String),
+    File(PathBuf),
+}
+
+/// Unlike `Url::to_file_path` this doesn't depend on the OS we run on: `file:///C:/dir` is a drive path,
+/// `file://server/share/dir` is a UNC path (Windows only) and `file://localhost/dir` is the same as `file:///dir`
+fn file_url_to_path(file_url: &str) -> Result<PathBuf, String> {
+    let url = url::Url::parse(file_url).map_err(|e| format!("invalid path URL {file_url}: {e}"))?;
+    let path = percent_decode_str(url.path()).decode_utf8()
+        .map_err(|e| format!("invalid path URL {file_url}: {e}"))?;
+    match url.host_str() {
+        None | Some("") | Some("localhost") => {
+            let bytes = path.as_bytes();
+            let is_drive_path = bytes.len() >= 3 && bytes[0] == b'/' && bytes[1].is_ascii_alphabetic() && bytes[2] == b':';
+            Ok(PathBuf::from(if is_drive_path { &path[1..] } else { path.as_ref() }))
+        }
+        Some(host) if cfg!(windows) => Ok(PathBuf::from(format!(r"\\{host}{}", path.replace('/', r"\")))),
+        Some(host) => Err(format!("{file_url} points to a network share on \"{host}\", only local files are supported")),
+    }
+}
+
+const FAKE_CHARS_PER_TOKEN_PREFIX: &str = "fake:chars_per_token=";
+
+/// Payload of `data:[<mediatype>][;base64],<data>`
+fn decode_data_uri(data_uri: &str) -> Result<Vec<u8>, String> {
+    let (header, payload) = data_uri.strip_prefix("data:").unwrap_or(data_uri).split_once(',')
+        .ok_or_else(|| "data: URI has no ',' before the tokenizer".to_string())?;
+    if header.ends_with(";base64") {
+        base64::engine::general_purpose::STANDARD.decode(payload.trim())
+            .map_err(|e| format!("data: URI has invalid base64: {e}"))
+    } else {
+        Ok(percent_decode_str(payload).collect())
+    }
+}
+
+pub fn parse_tokenizer_source(tokenizer: &str) -> Result<TokenizerSource, String> {
+    Ok(match tokenizer {
+        "" => TokenizerSource::Empty,
+        fake_tok if fake_tok.starts_with(FAKE_CHARS_PER_TOKEN_PREFIX) => {
+            let chars_per_token = fake_tok.strip_prefix(FAKE_CHARS_PER_TOKEN_PREFIX).unwrap().trim().parse::<usize>()
+                .ok().filter(|n| *n > 0)
+                .ok_or_else(|| format!("{fake_tok}: chars_per_token must be a positive integer"))?;
+            TokenizerSource::FakeCharsPerToken(chars_per_token)
+        }
+        fake_tok if fake_tok.starts_with("fake") => TokenizerSource::Fake,
+        data_tok if data_tok.starts_with("data:") => TokenizerSource::Data(decode_data_uri(data_tok)?),
+        hf_tok if hf_tok.starts_with("hf://") => TokenizerSource::Hf(hf_tok.strip_prefix("hf://").unwrap().to_string()),
+        repo_tok if repo_tok.starts_with("hf-repo:") => {
+            let repo = repo_tok.strip_prefix("hf-repo:").unwrap().trim().trim_matches('/');
+            if repo.is_empty() {
+                return Err(format!("{repo_tok} has no repo id"));
+            }
+            TokenizerSource::HfRepo(repo.to_string())
+        }
+        http_tok if http_tok.starts_with("http://") || http_tok.starts_with("https://") => {
+            TokenizerSource::Http(http_tok.to_string())
+        }
+        gguf_tok if gguf_tok.starts_with("gguf://") => TokenizerSource::File(PathBuf::from(gguf_tok.strip_prefix("gguf://").unwrap())),
+        file_tok if file_tok.starts_with("file://") => TokenizerSource::File(file_url_to_path(file_tok)?),
+        file_tok => TokenizerSource::File(PathBuf::from(file_tok)),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenizerFileFormat {
+    Json,
+    Gguf,
+    /// GPT-2 layout without a tokenizer.json: `vocab.json` with `merges.txt` next to it
+    VocabMerges,
+}
+
+const VOCAB_FILENAME: &str = "vocab.json";
+const MERGES_FILENAME: &str = "merges.txt";
+
+/// What `cached_tokenizer` would do for a model: download `url` (if any) to `path` and load it as `format`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenizerPlan {
+    pub source: TokenizerSource,
+    pub url: Option<String>,
+    pub path: Option<PathBuf>,
+    pub format: Option<TokenizerFileFormat>,
+}
+
+/// `tokenizer` followed by `tokenizer_fallbacks`, e.g. `"tokenizer": "/models/qwen/tokenizer.json",
+/// "tokenizer_fallbacks": ["hf://Qwen/Qwen2.5-Coder-1.5B"]`, they are tried in order.
+/// With `tokenizer_prefer_local` the local ones go first, otherwise keeping their order
+pub fn tokenizer_candidates(model_rec: &BaseModelRecord) -> Vec<&str> {
+    let mut candidates: Vec<&str> = std::iter::once(&model_rec.tokenizer)
+        .chain(&model_rec.tokenizer_fallbacks)
+        .map(|candidate| candidate.trim())
+        .filter(|candidate| !candidate.is_empty())
+        .collect();
+    if model_rec.tokenizer_prefer_local {
+        candidates.sort_by_key(|candidate| !is_local_tokenizer_source(candidate));
+    }
+    candidates
+}
+
+fn is_local_tokenizer_source(tokenizer: &str) -> bool {
+    !matches!(parse_tokenizer_source(tokenizer), Ok(TokenizerSource::Hf(_) | TokenizerSource::HfRepo(_) | TokenizerSource::Http(_)))
+}
+
+/// Resolves the `tokenizer` field of a model record without downloading or loading anything,
+/// for a list of candidates that's the first one. HuggingFace tokenizers already in `hf_hub_cache` are used from there
+pub fn resolve_tokenizer_plan(
+    model_rec: &BaseModelRecord,
+    cache_dir: &Path,
+    hf_hub_cache: Option<&Path>,
+    hf_tokenizer_template: &str,
+) -> Result<TokenizerPlan, TokenizerError> {
+    let candidates = tokenizer_candidates(model_rec);
+    let tokenizer = candidates.first().copied().unwrap_or_default();
+    resolve_candidate_plan(model_rec, tokenizer, candidates.len() > 1, cache_dir, hf_hub_cache, hf_tokenizer_template)
+}
+
+/// Resolves one of `tokenizer_candidates`. When the model has several, each remote one gets a cache path of its own
+fn resolve_candidate_plan(
+    model_rec: &BaseModelRecord,
+    tokenizer: &str,
+    is_one_of_many: bool,
+    cache_dir: &Path,
+    hf_hub_cache: Option<&Path>,
+    hf_tokenizer_template: &str,
+) -> Result<TokenizerPlan, TokenizerError> {
+    let model_id = strip_model_from_finetune(&model_rec.id);
+    let source = parse_tokenizer_source(tokenizer).map_err(TokenizerError::UnsupportedFormat)?;
+    let download_path = |cache_dir: &Path, url: &str| match is_one_of_many {
+        true => tokenizer_candidate_cache_path(cache_dir, &model_id, url),
+        false => tokenizer_cache_path(cache_dir, &model_id),
+    };
+    let (url, path) = match &source {
+        TokenizerSource::Empty | TokenizerSource::Fake | TokenizerSource::FakeCharsPerToken(_) | TokenizerSource::Data(_) => (None, None),
+        TokenizerSource::Hf(repo) | TokenizerSource::HfRepo(repo) => {
+            let revision = model_rec.tokenizer_revision.as_deref();
+            let filename = model_rec.tokenizer_filename.as_deref();
+            let template = match &source {
+                TokenizerSource::HfRepo(_) => default_hf_tokenizer_template(),
+                _ => hf_tokenizer_template.to_string(),
+            };
+            // the hub cache has huggingface.co files, a custom template may serve other files under the same names
+            let hf_hub_cache = hf_hub_cache.filter(|_| template == default_hf_tokenizer_template());
+            match hf_hub_cache.and_then(|hub_cache| hf_hub_cached_file(hub_cache, repo, revision, filename)) {
+                Some(path) => (None, Some(path)),
+                None => {
+                    let url = hf_tokenizer_url(&template, repo, revision, filename);
+                    let path = download_path(cache_dir, &url);
+                    (Some(url), Some(path))
+                }
+            }
+        }
+        TokenizerSource::Http(url) => (Some(url.clone()), Some(download_path(cache_dir, url))),
+        TokenizerSource::File(file) => {
+            let path = canonical_path(file.to_string_lossy());
+            // a directory is taken for a GPT-2 style vocab.json + merges.txt pair
+            let path = if path.is_dir() { path.join(VOCAB_FILENAME) } else { path };
+            (None, Some(path))
+        }
+    };
+    let format = path.as_ref().map(|path| match path.extension() {
+        Some(ext) if ext == "gguf" => TokenizerFileFormat::Gguf,
+        _ if path.file_name().is_some_and(|name| name == VOCAB_FILENAME) => TokenizerFileFormat::VocabMerges,
+        _ => TokenizerFileFormat::Json,
+    });
+    Ok(TokenizerPlan { source, url, path, format })
+}
+
+/// Cuts text into pieces of `chars_per_token` chars, each one is the same unknown token:
+/// the count is always `ceil(chars / chars_per_token)`
+fn fake_tokenizer(chars_per_token: usize) -> Result<Tokenizer, TokenizerError> {
+    let vocab = ahash::AHashMap::from_iter([(FAKE_UNK_TOKEN.to_string(), 0)]);
+    let model = WordLevel::builder()
+        .vocab(vocab)
+        .unk_token(FAKE_UNK_TOKEN.to_string())
+        .build()
+        .map_err(|e| TokenizerError::Parse(format!("failed to build fake tokenizer: {e}")))?;
+    let pieces = Split::new(
+        SplitPattern::Regex(format!(r"[\s\S]{{1,{chars_per_token}}}")), SplitDelimiterBehavior::Isolated, false,
+    ).map_err(|e| TokenizerError::Parse(format!("failed to build fake tokenizer: {e}")))?;
+    let mut tokenizer = Tokenizer::new(model);
+    tokenizer.with_pre_tokenizer(Some(pieces));
+    Ok(tokenizer)
+}
+
+const FAKE_UNK_TOKEN: &str = "[UNK]";
+
+fn open_tokenizer_file(path: &Path, format: Option<TokenizerFileFormat>) -> Result<Tokenizer, TokenizerError> {
+    if !path.exists() {
+        return Err(TokenizerError::NotFound(path.display().to_string()));
+    }
+    match format {
+        Some(TokenizerFileFormat::Gguf) => gguf::tokenizer_from_gguf(path),
+        Some(TokenizerFileFormat::VocabMerges) => tokenizer_from_vocab_and_merges(path),
+        _ => Tokenizer::from_file(path)
+            .map_err(|e| TokenizerError::Parse(e.to_string())),
+    }
+}
+
+/// Byte-level BPE from `vocab.json` and the `merges.txt` next to it, set up like GPT-2
+fn tokenizer_from_vocab_and_merges(vocab_path: &Path) -> Result<Tokenizer, TokenizerError> {
+    let merges_path = vocab_path.with_file_name(MERGES_FILENAME);
+    if !merges_path.exists() {
+        return Err(TokenizerError::NotFound(merges_path.display().to_string()));
+    }
+    let bpe = BPE::from_file(&vocab_path.to_string_lossy(), &merges_path.to_string_lossy())
+        .build()
+        .map_err(|e| TokenizerError::Parse(format!("failed to build BPE from {}: {e}", vocab_path.display())))?;
+    let mut tokenizer = Tokenizer::new(bpe);
+    tokenizer.with_pre_tokenizer(Some(ByteLevel::new(false, true, true)));
+    tokenizer.with_decoder(Some(ByteLevelDecoder::default()));
+    Ok(tokenizer)
+}
+
+/// Gated HuggingFace tokenizers use `HF_TOKEN` like the rest of the HuggingFace tooling, unless the model sets its own key.
+/// An `hf://` template may point elsewhere, the token is only sent to huggingface.co
+fn tokenizer_api_key(source: &TokenizerSource, url: &str, configured: &str, hf_token_env: Option<String>) -> String {
+    let is_huggingface = url::Url::parse(url).ok()
+        .and_then(|url| url.host_str().map(|host| host == "huggingface.co" || host.ends_with(".huggingface.co")))
+        .unwrap_or(false);
+    match (source, hf_token_env) {
+        (TokenizerSource::HfRepo(_), Some(hf_token)) if configured.trim().is_empty() => hf_token,
+        (TokenizerSource::Hf(_), Some(hf_token)) if configured.trim().is_empty() && is_huggingface => hf_token,
+        _ => configured.to_string(),
+    }
+}
+
+/// `resolve_candidate_plan` that checks a hub cache file against `tokenizer_sha256` like a download would be,
+/// a file that doesn't match is left alone and the tokenizer is downloaded to the engine cache instead
+async fn resolve_checked_plan(
+    cx: &TokenizerLoadContext<'_>,
+    model_rec: &BaseModelRecord,
+    tokenizer: &str,
+    is_one_of_many: bool,
+) -> Result<TokenizerPlan, TokenizerError> {
+    let resolve = |hf_hub_cache| resolve_candidate_plan(
+        model_rec, tokenizer, is_one_of_many, &cx.config.cache_dir, hf_hub_cache, cx.hf_tokenizer_template,
+    );
+    let plan = resolve(cx.config.hf_hub_cache.as_deref())?;
+    let is_from_hub_cache = matches!(plan.source, TokenizerSource::Hf(_) | TokenizerSource::HfRepo(_)) && plan.url.is_none();
+    if let Some(path) = plan.path.as_ref().filter(|_| is_from_hub_cache) {
+        if let Err(e) = check_sha256(path, model_rec.tokenizer_sha256.as_deref()).await {
+            tracing::warn!("not using {} from the HuggingFace hub cache: {}", path.display(), e);
+            return resolve(None);
+        }
+    }
+    Ok(plan)
+}
+
+/// What loading a tokenizer needs besides the model record, borrowed from the `TokenizerRegistry` for one load
+struct TokenizerLoadContext<'a> {
+    config: &'a TokenizerRegistryConfig,
+    http_client: &'a reqwest::Client,
+    hf_tokenizer_template: &'a str,
+    metrics: &'a TokenizerMetrics,
+    progress: Option<&'a TokenizerDownloadProgress>,
+    cancel: Option<&'a CancellationToken>,
+}
+
+/// Loads the first of `tokenizer_candidates` that works and tells which one it was,
+/// the error is the one of the last candidate
+async fn load_tokenizer_and_source(
+    cx: &TokenizerLoadContext<'_>,
+    model_rec: &BaseModelRecord,
+    model_id: &str,
+) -> Result<(Option<Arc<Tokenizer>>, String), TokenizerError> {
+    let candidates = tokenizer_candidates(model_rec);
+    let is_one_of_many = candidates.len() > 1;
+    let mut last_error = TokenizerError::EmptyTokenizer(model_id.to_string());
+    for candidate in candidates {
+        if candidate.contains(',') && !candidate.starts_with("data:") {
+            tracing::warn!("tokenizer \"{}\" for {} contains a comma, several sources go into tokenizer_fallbacks", candidate, model_id);
+        }
+        let candidate_rec = BaseModelRecord { tokenizer: candidate.to_string(), tokenizer_fallbacks: vec![], ..model_rec.clone() };
+        let loaded = match resolve_checked_plan(cx, &candidate_rec, candidate, is_one_of_many).await {
+            Ok(plan) => load_tokenizer_source(cx, &candidate_rec, plan, model_id).await,
+            Err(e) => Err(e),
+        };
+        match loaded {
+            Ok(tokenizer) => {
+                if is_one_of_many {
+                    tracing::info!("using tokenizer \"{}\" for {}", candidate, model_id);
+                }
+                return Ok((tokenizer, candidate.to_string()));
+            }
+            Err(e) if !is_one_of_many => return Err(e),
+            Err(TokenizerError::Cancelled) => return Err(TokenizerError::Cancelled),
+            Err(e) => {
+                tracing::warn!("tokenizer \"{}\" for {} failed, trying the next one: {}", candidate, model_id, e);
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+async fn load_tokenizer_source(
+    cx: &TokenizerLoadContext<'_>,
+    model_rec: &BaseModelRecord,
+    plan: TokenizerPlan,
+    model_id: &str,
+) -> Result<Option<Arc<Tokenizer>>, TokenizerError> {
+    match &plan.source {
+        TokenizerSource::Empty => return Err(TokenizerError::EmptyTokenizer(model_id.to_string())),
+        TokenizerSource::Fake => return Ok(None),
+        TokenizerSource::FakeCharsPerToken(chars_per_token) => return Ok(Some(Arc::new(fake_tokenizer(*chars_per_token)?))),
+        TokenizerSource::Data(bytes) => return Ok(Some(Arc::new(tokenizer_from_bytes(bytes)?))),
+        _ => {}
+    }
+    let tok_file_path = plan.path.unwrap_or_default();
+    // a cached copy that doesn't parse fails `check_json_file` there and is downloaded again
+    if let Some(tok_url) = &plan.url {
+        let api_key = tokenizer_api_key(&plan.source, tok_url, &model_rec.tokenizer_api_key, cx.config.env.get("HF_TOKEN"));
+        let downloaded = try_download_tokenizer_file_and_open(
+            cx.http_client, tok_url, &api_key, &tok_file_path, model_rec.tokenizer_sha256.as_deref(), cx.config.offline,
+            &cx.config.download_policy, cx.config.tmp_dir.as_deref(), cx.progress, cx.cancel,
+        ).await.inspect_err(|_| { cx.metrics.download_failures.fetch_add(1, AtomicOrdering::Relaxed); })?;
+        if downloaded {
+            cx.metrics.downloads.fetch_add(1, AtomicOrdering::Relaxed);
+        }
+    }
+
+    tracing::info!("loading tokenizer \"{}\"", tok_file_path.display());
+    let tokenizer = without_truncation_and_padding(open_tokenizer_file(&tok_file_path, plan.format)?);
+    tracing::info!("loaded tokenizer for {}: {}", model_id, describe(&tokenizer, &model_rec.tokenizer));
+    Ok(Some(Arc::new(tokenizer)))
+}
+
+/// Estimate as length / 3.5, since 3 is reasonable estimate for code, and 4 for natural language.
+/// Empty text is 0 tokens, anything else at least 1
+fn estimate_tokens(text: &str) -> usize { estimate_tokens_by_len(text.len()) }
+
+fn estimate_tokens_by_len(len: usize) -> usize {
+    if len == 0 { 0 } else { 1 + len * 2 / 7 }
+}
+
+/// What the token budget logic needs from a tokenizer, lets tests count without real tokenizer files
+pub trait TokenCounter {
+    fn count(&self, text: &str) -> Result<usize, String>;
+    fn encode(&self, text: &str, add_special: bool) -> Result<Encoding, String>;
+}
+
+impl TokenCounter for Tokenizer {
+    fn count(&self, text: &str) -> Result<usize, String> {
+        count_tokens(self, text, false)
+    }
+
+    fn encode(&self, text: &str, add_special: bool) -> Result<Encoding, String> {
+        self.encode_fast(text, add_special)
+            .map_err(|e| format!("Encoding error: {e}"))
+    }
+}
+
+pub fn count_text_tokens(
+    tokenizer: Option<&Tokenizer>,
+    text: &str,
+) -> Result<usize, String> {
+    count_text_tokens_with(tokenizer.map(|t| t as &dyn TokenCounter), text)
+}
+
+/// Same as `count_text_tokens`, but without a tokenizer it's an error instead of an estimate,
+/// for budgets that must not be guessed
+pub fn count_text_tokens_strict(
+    tokenizer: Option<&Tokenizer>,
+    text: &str,
+) -> Result<usize, String> {
+    match tokenizer {
+        Some(tokenizer) => TokenCounter::count(tokenizer, text),
+        None => Err("no tokenizer to count tokens with, refusing to estimate".to_string()),
+    }
+}
+
+/// Sum of `count_text_tokens_with_fallback` over `chunks`, borrowing the tokenizer once for all of them
+pub fn count_total_tokens<'a>(
+    tokenizer: Option<&Tokenizer>,
+    chunks: impl IntoIterator<Item = &'a str>,
+) -> usize {
+    chunks.into_iter()
+        .map(|chunk| count_with_fallback(tokenizer.map(|t| t as &dyn TokenCounter), chunk))
+        .sum()
+}
+
+pub fn count_text_tokens_with(
+    counter: Option<&dyn TokenCounter>,
+    text: &str,
+) -> Result<usize, String> {
+    match counter {
+        Some(counter) => counter.count(text),
+        None => {
+            Ok(estimate_tokens(text))
+        }
+    }
+}
+
+/// Same number as `encode_fast(text, add_special).len()` minus padding, but runs only normalization,
+/// pre-tokenization and the model, without building an `Encoding` (token strings, offsets, masks).
+/// Truncation and the post-processor's special tokens are counted, padding tokens never are
+pub fn count_tokens(tokenizer: &Tokenizer, text: &str, add_special: bool) -> Result<usize, String> {
+    let pretokenized = tokenize_text(tokenizer, text, true, None)?;
+    let count: usize = pretokenized.get_splits(OffsetReferential::Original, OffsetType::Byte).iter()
+        .map(|(_, _, tokens)| tokens.as_ref().map_or(0, |tokens| tokens.len()))
+        .sum();
+    let special_count = match tokenizer.get_post_processor() {
+        Some(post_processor) if add_special => post_processor.added_tokens(false),
+        _ => 0,
+    };
+    let count = match tokenizer.get_truncation() {
+        Some(truncation) => count.min(truncation.max_length.saturating_sub(special_count)),
+        None => count,
+    };
+    Ok(count + special_count)
+}
+
+pub fn count_text_tokens_with_fallback(
+    tokenizer: Option<&Tokenizer>,
     text: &str,
 ) -> usize {
-    count_text_tokens(tokenizer, text).unwrap_or_else(|e| {
-        tracing::error!("{e}");
+    count_with_fallback(tokenizer.map(|t| t as &dyn TokenCounter), text)
+}
+
+fn count_with_fallback(counter: Option<&dyn TokenCounter>, text: &str) -> usize {
+    count_text_tokens_with(counter, text).unwrap_or_else(|e| {
+        match count_repeated_error(&FALLBACK_ERRORS_SEEN, &e) {
+            Some(1) => tracing::error!("{e}"),
+            Some(times) => tracing::error!("{e} (repeated {times} times)"),
+            None => {}
+        }
         estimate_tokens(text)
     })
-}
\ No newline at end of file
+}
+
+// a broken tokenizer fails the same way for every chunk, don't flood the log with it
+const FALLBACK_ERRORS_CAPACITY: usize = 1000;
+
+lazy_static! {
+    static ref FALLBACK_ERRORS_SEEN: StdMutex<HashMap<String, usize>> = StdMutex::new(HashMap::new());
+}
+
+/// Counts occurrences of `error`, returns the count when it's worth logging: the first time, then at 10, 100, ...
+fn count_repeated_error(seen: &StdMutex<HashMap<String, usize>>, error: &str) -> Option<usize> {
+    let mut seen = seen.lock().unwrap();
+    if seen.len() >= FALLBACK_ERRORS_CAPACITY && !seen.contains_key(error) {
+        seen.clear();
+    }
+    let times = seen.entry(error.to_string()).or_insert(0);
+    *times += 1;
+    let is_power_of_10 = times.to_string().trim_end_matches('0') == "1";
+    is_power_of_10.then_some(*times)
+}
+
+/// Running token count of a buffer that only grows, e.g. a context that tool outputs are appended to.
+///
+/// Text up to the last whitespace is counted once and never encoded again, only the tail after it
+/// is re-encoded on the next `append`. This assumes no token spans that boundary: true for pre-tokenizers
+/// that split on whitespace (byte-level BPE keeps the space with the following word), but the total can be
+/// off by a token or so per boundary for tokenizers that merge across whitespace.
+pub struct IncrementalCounter<'a> {
+    tokenizer: Option<&'a Tokenizer>,
+    counted_tokens: usize,
+    total_len: usize,
+    tail: String,
+}
+
+impl<'a> IncrementalCounter<'a> {
+    pub fn new(tokenizer: Option<&'a Tokenizer>) -> Self {
+        IncrementalCounter { tokenizer, counted_tokens: 0, total_len: 0, tail: String::new() }
+    }
+
+    /// Appends `text`, returns the token count of everything appended so far
+    pub fn append(&mut self, text: &str) -> Result<usize, String> {
+        self.total_len += text.len();
+        let Some(tokenizer) = self.tokenizer else {
+            return Ok(estimate_tokens_by_len(self.total_len));
+        };
+        self.tail.push_str(text);
+        if let Some(boundary) = self.tail.rfind(char::is_whitespace).filter(|boundary| *boundary > 0) {
+            self.counted_tokens += count_tokens(tokenizer, &self.tail[..boundary], false)?;
+            self.tail.drain(..boundary);
+        }
+        Ok(self.counted_tokens + count_tokens(tokenizer, &self.tail, false)?)
+    }
+}
+
+/// Encoding more than this blocks a tokio worker for milliseconds, use the async variants for longer texts
+pub const ASYNC_ENCODE_THRESHOLD_BYTES: usize = 16 * 1024;
+
+/// Same as `count_text_tokens`, but long texts are encoded on the blocking thread pool
+pub async fn count_text_tokens_async(
+    tokenizer: Option<Arc<Tokenizer>>,
+    text: String,
+) -> Result<usize, String> {
+    if tokenizer.is_none() || text.len() < ASYNC_ENCODE_THRESHOLD_BYTES {
+        return count_text_tokens(tokenizer.as_deref(), &text);
+    }
+    tokio::task::spawn_blocking(move || count_text_tokens(tokenizer.as_deref(), &text)).await
+        .map_err(|e| format!("Encoding task failed: {e}"))?
+}
+
+/// `Tokenizer::encode_fast` on the blocking thread pool, for texts longer than `ASYNC_ENCODE_THRESHOLD_BYTES`
+pub async fn encode_fast_async(
+    tokenizer: Arc<Tokenizer>,
+    text: String,
+    add_special: bool,
+) -> Result<Encoding, String> {
+    let encode = move || tokenizer.encode_fast(text.as_str(), add_special)
+        .map_err(|e| format!("Encoding error: {e}"));
+    tokio::task::spawn_blocking(encode).await
+        .map_err(|e| format!("Encoding task failed: {e}"))?
+}
+
+/// `Tokenizer::encode_fast` with a time cap for untrusted input, with the tokenizer's own truncation and padding.
+/// The deadline is checked between pre-tokens, so it's best-effort: normalization and pre-tokenization
+/// of the whole text, or one huge pre-token (a tokenizer without a pre-tokenizer has just one), are not interrupted
+pub fn encode_fast_with_deadline(
+    tokenizer: &Tokenizer,
+    text: &str,
+    add_special: bool,
+    deadline: Instant,
+) -> Result<Encoding, String> {
+    encode_pretokenized(tokenizer, text, add_special, tokenizer.get_truncation(), tokenizer.get_padding(), Some(deadline))
+}
+
+/// Same as `encode_fast`, but `get_offsets()` are char indices into `text` instead of byte indices,
+/// which is what editors work with. Special tokens added around the text have `(0, 0)` offsets
+pub fn encode_with_char_offsets(tokenizer: &Tokenizer, text: &str, add_special: bool) -> Result<Encoding, String> {
+    tokenizer.encode_char_offsets(text, add_special)
+        .map_err(|e| format!("Encoding error: {e}"))
+}
+
+/// Encodes using the tokenizer's own truncation settings, also returns how many tokens the truncation dropped
+pub fn encode_with_overflow(
+    tokenizer: &Tokenizer,
+    text: &str,
+    add_special: bool,
+) -> Result<(Encoding, usize), String> {
+    let encoding = tokenizer.encode_fast(text, add_special)
+        .map_err(|e| format!("Encoding error: {e}"))?;
+    // each overflowing piece repeats `stride` tokens of the previous one, and gets its own special tokens
+    let stride = tokenizer.get_truncation().map(|t| t.stride).unwrap_or(0);
+    let dropped = encoding.get_overflowing().iter()
+        .map(|piece| piece.get_special_tokens_mask().iter().filter(|m| **m == 0).count().saturating_sub(stride))
+        .sum();
+    Ok((encoding, dropped))
+}
+
+/// Encodes all texts in parallel, results are in the same order as the input
+pub fn encode_batch(
+    tokenizer: &Tokenizer,
+    texts: &[&str],
+    add_special: bool,
+) -> Result<Vec<Encoding>, String> {
+    tokenizer.encode_batch_fast(texts.to_vec(), add_special)
+        .map_err(|e| format!("Encoding error: {e}"))
+}
+
+/// Token ids of `text`, without the tokens, offsets and masks an `Encoding` carries
+pub fn encode_ids(tokenizer: &Tokenizer, text: &str, add_special: bool) -> Result<Vec<u32>, String> {
+    if add_special || tokenizer.get_truncation().is_some() {
+        return tokenizer.encode_fast(text, add_special)
+            .map(|encoding| encoding.get_ids().to_vec())
+            .map_err(|e| format!("Encoding error: {e}"));
+    }
+    let pretokenized = tokenize_text(tokenizer, text, true, None)?;
+    Ok(pretokenized.get_splits(OffsetReferential::Original, OffsetType::Byte).into_iter()
+        .flat_map(|(_, _, tokens)| tokens.iter().flatten().map(|token| token.id))
+        .collect())
+}
+
+/// The longest prefix of `text` that fits into `max_tokens` and its token count. It's cut from `text` itself
+/// at a token boundary, decoding could change whitespace, and a character split between tokens is dropped whole
+pub fn truncate_to_tokens(tokenizer: &Tokenizer, text: &str, max_tokens: usize) -> Result<(String, usize), String> {
+    let encoding = encode_piece(tokenizer, text)?;
+    if encoding.len() <= max_tokens {
+        return Ok((text.to_string(), encoding.len()));
+    }
+    for kept in (1..=max_tokens).rev() {
+        let mut end = encoding.get_offsets()[kept].0.min(text.len());
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        let prefix = &text[..end];
+        let count = count_tokens(tokenizer, prefix, false)?;
+        if count <= max_tokens {
+            return Ok((prefix.to_string(), count));
+        }
+    }
+    Ok((String::new(), 0))
+}
+
+/// Token count of each `\n`-separated line of `text`, from a single encoding so tokens merged across a newline
+/// are counted once: a token belongs to the line its first byte is on, the newline is part of its line
+pub fn line_token_counts(tokenizer: &Tokenizer, text: &str) -> Result<Vec<usize>, String> {
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(pos, _)| pos + 1))
+        .collect();
+    let mut counts = vec![0; line_starts.len()];
+    for (start, _) in encode_piece(tokenizer, text)?.get_offsets() {
+        let line = line_starts.partition_point(|line_start| line_start <= start) - 1;
+        counts[line] += 1;
+    }
+    Ok(counts)
+}
+
+/// Keeps about half of `max_tokens` from the start of `text` and the rest from the end, joined with `marker`.
+/// The marker's own tokens come out of the budget, the result never has more than `max_tokens` tokens.
+pub fn truncate_middle(tokenizer: &Tokenizer, text: &str, max_tokens: usize, marker: &str) -> Result<String, String> {
+    let encoding = encode_piece(tokenizer, text)?;
+    if encoding.len() <= max_tokens {
+        return Ok(text.to_string());
+    }
+    let marker_tokens = count_tokens(tokenizer, marker, false)?;
+    let mut budget = max_tokens.saturating_sub(marker_tokens);
+    loop {
+        let head_tokens = budget.div_ceil(2);
+        let (head, _) = truncate_to_tokens(tokenizer, text, head_tokens)?;
+        let tail = tail_within_tokens(tokenizer, text, &encoding, budget - head_tokens)?;
+        let result = format!("{head}{marker}{tail}");
+        // tokens can merge differently where the pieces meet
+        if count_tokens(tokenizer, &result, false)? <= max_tokens {
+            return Ok(result);
+        }
+        if budget == 0 {
+            return Ok(String::new());
+        }
+        budget -= 1;
+    }
+}
+
+fn tail_within_tokens<'a>(tokenizer: &Tokenizer, text: &'a str, encoding: &Encoding, max_tokens: usize) -> Result<&'a str, String> {
+    for dropped in encoding.len().saturating_sub(max_tokens)..encoding.len() {
+        let mut start = encoding.get_offsets()[dropped].0.min(text.len());
+        while !text.is_char_boundary(start) {
+            start += 1;
+        }
+        let suffix = &text[start..];
+        if count_tokens(tokenizer, suffix, false)? <= max_tokens {
+            return Ok(suffix);
+        }
+    }
+    Ok("")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    UnknownId(u32),
+    /// The ids start or end in the middle of a multibyte character, common while streaming
+    InvalidUtf8,
+    Tokenizer(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnknownId(id) => write!(f, "Decoding error: token id {id} is not in the vocabulary"),
+            DecodeError::InvalidUtf8 => write!(f, "Decoding error: tokens don't form valid UTF-8"),
+            DecodeError::Tokenizer(msg) => write!(f, "Decoding error: {msg}"),
+        }
+    }
+}
+
+impl From<DecodeError> for String {
+    fn from(err: DecodeError) -> Self {
+        err.to_string()
+    }
+}
+
+/// Fails with `InvalidUtf8` if the tokens split a character, `decode_lossy` puts U+FFFD there instead
+pub fn decode_tokens(
+    tokenizer: &Tokenizer,
+    ids: &[u32],
+    skip_special: bool,
+) -> Result<String, DecodeError> {
+    let text = decode_lossy(tokenizer, ids, skip_special)?;
+    // decoders already replace broken bytes with U+FFFD, but the text may have it too: look at the bytes themselves
+    if text.contains(char::REPLACEMENT_CHARACTER) {
+        let bytes = decoded_bytes(tokenizer, ids, skip_special);
+        if bytes.is_some_and(|bytes| std::str::from_utf8(&bytes).is_err()) {
+            return Err(DecodeError::InvalidUtf8);
+        }
+    }
+    Ok(text)
+}
+
+lazy_static! {
+    /// Inverse of GPT-2's `bytes_to_unicode`: printable bytes stand for themselves, the rest are moved past U+00FF
+    static ref BYTE_LEVEL_CHAR_BYTES: HashMap<char, u8> = {
+        let is_printable = |b: u8| matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+        let mut moved = 0;
+        (0..=255u8).map(|b| if is_printable(b) {
+            (char::from(b), b)
+        } else {
+            moved += 1;
+            (char::from_u32(0xFF + moved).unwrap(), b)
+        }).collect()
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ByteDecoding {
+    /// Every byte is a printable char, see `BYTE_LEVEL_CHAR_BYTES`
+    ByteLevel,
+    /// Bytes missing from the vocab are `<0xNN>` tokens
+    ByteFallback,
+}
+
+fn byte_decoding(decoder: &DecoderWrapper) -> Option<ByteDecoding> {
+    match decoder {
+        DecoderWrapper::ByteLevel(_) => Some(ByteDecoding::ByteLevel),
+        DecoderWrapper::ByteFallback(_) => Some(ByteDecoding::ByteFallback),
+        DecoderWrapper::Sequence(sequence) => sequence.get_decoders().iter().find_map(byte_decoding),
+        _ => None,
+    }
+}
+
+/// The bytes `ids` stand for, before the decoder makes a string of them. `None` for decoders that don't
+/// work on bytes, their tokens are whole characters and can't split one
+fn decoded_bytes(tokenizer: &Tokenizer, ids: &[u32], skip_special: bool) -> Option<Vec<u8>> {
+    let decoding = byte_decoding(tokenizer.get_decoder()?)?;
+    let tokens = ids.iter()
+        .filter_map(|id| tokenizer.id_to_token(*id))
+        .filter(|token| !skip_special || !is_special_str(tokenizer, token));
+    let mut bytes = vec![];
+    for token in tokens {
+        let token_bytes = match decoding {
+            ByteDecoding::ByteLevel => token.chars().map(|c| BYTE_LEVEL_CHAR_BYTES.get(&c).copied()).collect(),
+            ByteDecoding::ByteFallback => token.strip_prefix("<0x").and_then(|hex| hex.strip_suffix('>'))
+                .filter(|hex| hex.len() == 2)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .map(|byte| vec![byte]),
+        };
+        bytes.extend(token_bytes.unwrap_or_else(|| token.into_bytes()));
+    }
+    Some(bytes)
+}
+
+pub fn decode_lossy(
+    tokenizer: &Tokenizer,
+    ids: &[u32],
+    skip_special: bool,
+) -> Result<String, DecodeError> {
+    if let Some(unknown_id) = ids.iter().find(|id| tokenizer.id_to_token(**id).is_none()) {
+        return Err(DecodeError::UnknownId(*unknown_id));
+    }
+    tokenizer.decode(ids, skip_special)
+        .map_err(|e| DecodeError::Tokenizer(e.to_string()))
+}
+
+/// Decodes ids one at a time for streaming, text is held back until the bytes of a character are complete.
+/// Recent ids stay as context because decoders like Metaspace treat the first token differently.
+pub struct StreamDecoder {
+    tokenizer: Arc<Tokenizer>,
+    skip_special: bool,
+    ids: Vec<u32>,
+    prefix: String,
+    prefix_index: usize,
+}
+
+impl StreamDecoder {
+    pub fn new(tokenizer: Arc<Tokenizer>, skip_special: bool) -> Self {
+        StreamDecoder { tokenizer, skip_special, ids: vec![], prefix: String::new(), prefix_index: 0 }
+    }
+
+    pub fn push(&mut self, id: u32) -> Result<Option<String>, DecodeError> {
+        self.ids.push(id);
+        let text = decode_lossy(&self.tokenizer, &self.ids, self.skip_special)?;
+        if text.len() <= self.prefix.len() || text.ends_with(char::REPLACEMENT_CHARACTER) {
+            return Ok(None);
+        }
+        let new_text = text.get(self.prefix.len()..).filter(|_| text.starts_with(&self.prefix))
+            .ok_or_else(|| DecodeError::Tokenizer(format!("decoded text {text:?} doesn't continue {:?}", self.prefix)))?
+            .to_string();
+        let new_prefix_index = self.ids.len() - self.prefix_index;
+        self.ids.drain(..self.prefix_index);
+        self.prefix = decode_lossy(&self.tokenizer, &self.ids, self.skip_special)?;
+        self.prefix_index = new_prefix_index;
+        Ok(Some(new_text))
+    }
+
+    /// Whatever is still held back, an unfinished character becomes U+FFFD
+    pub fn finish(self) -> Result<String, DecodeError> {
+        let text = decode_lossy(&self.tokenizer, &self.ids, self.skip_special)?;
+        Ok(text.get(self.prefix.len()..).unwrap_or_default().to_string())
+    }
+}
+
+/// What got loaded, for logs and support triage
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenizerInfo {
+    /// Model type inside tokenizer.json: "BPE", "WordPiece", "WordLevel" or "Unigram"
+    pub backend: String,
+    /// Where the tokenizer came from, `BaseModelRecord::tokenizer`
+    pub model_name: String,
+    /// Number of ids the tokenizer can produce, added and special tokens included
+    pub vocab_size: usize,
+}
+
+impl fmt::Display for TokenizerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} tokenizer \"{}\", vocab size {}", self.backend, self.model_name, self.vocab_size)
+    }
+}
+
+/// The model inside a tokenizer, cheap to get for every request, e.g. `tokenizer.as_deref().map(backend_kind)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenizerBackend {
+    Bpe,
+    WordPiece,
+    WordLevel,
+    Unigram,
+}
+
+impl TokenizerBackend {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TokenizerBackend::Bpe => "BPE",
+            TokenizerBackend::WordPiece => "WordPiece",
+            TokenizerBackend::WordLevel => "WordLevel",
+            TokenizerBackend::Unigram => "Unigram",
+        }
+    }
+}
+
+pub fn backend_kind(tokenizer: &Tokenizer) -> TokenizerBackend {
+    match tokenizer.get_model() {
+        ModelWrapper::BPE(_) => TokenizerBackend::Bpe,
+        ModelWrapper::WordPiece(_) => TokenizerBackend::WordPiece,
+        ModelWrapper::WordLevel(_) => TokenizerBackend::WordLevel,
+        ModelWrapper::Unigram(_) => TokenizerBackend::Unigram,
+    }
+}
+
+pub fn describe(tokenizer: &Tokenizer, model_name: &str) -> TokenizerInfo {
+    TokenizerInfo {
+        backend: backend_kind(tokenizer).as_str().to_string(),
+        model_name: model_name.to_string(),
+        vocab_size: tokenizer.get_vocab_size(true),
+    }
+}
+
+/// Special tokens such as `<|endoftext|>` with their ids, sorted by id
+pub fn special_tokens(tokenizer: &Tokenizer) -> Vec<(String, u32)> {
+    let mut special_tokens: Vec<(String, u32)> = tokenizer.get_added_tokens_decoder().into_iter()
+        .filter(|(_, token)| token.special)
+        .map(|(id, token)| (token.content, id))
+        .collect();
+    special_tokens.sort_by_key(|(_, id)| *id);
+    special_tokens
+}
+
+/// Encodes `text` where only the special tokens in `allowed` become special ids, other special tokens
+/// found in the text are encoded as plain text, e.g. `<|endoftext|>` typed by a user.
+/// Offsets of the pieces are joined as `Encoding::merge` does
+pub fn encode_with_special_policy(tokenizer: &Tokenizer, text: &str, allowed: &HashSet<String>) -> Result<Encoding, String> {
+    let mut specials: Vec<String> = special_tokens(tokenizer).into_iter().map(|(content, _)| content).collect();
+    specials.sort_by_key(|content| std::cmp::Reverse(content.len()));
+
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let next_special = specials.iter()
+            .filter_map(|special| rest.find(special.as_str()).map(|pos| (pos, special)))
+            .min_by_key(|(pos, _)| *pos);
+        let Some((pos, special)) = next_special else {
+            pieces.push(encode_piece(tokenizer, rest)?);
+            break;
+        };
+        if pos > 0 {
+            pieces.push(encode_piece(tokenizer, &rest[..pos])?);
+        }
+        let special_text = &rest[pos..pos + special.len()];
+        if allowed.contains(special) {
+            pieces.push(encode_piece(tokenizer, special_text)?);
+        } else {
+            pieces.push(encode_literally(tokenizer, special_text)?);
+        }
+        rest = &rest[pos + special.len()..];
+    }
+    Ok(Encoding::merge(pieces, true))
+}
+
+fn encode_piece(tokenizer: &Tokenizer, text: &str) -> Result<Encoding, String> {
+    // the inherent encode with offsets, not TokenCounter::encode
+    (**tokenizer).encode(text, false).map_err(|e| format!("Encoding error: {e}"))
+}
+
+/// Normalizer, pre-tokenizer and model only, the added vocabulary is skipped so special tokens stay text
+fn encode_literally(tokenizer: &Tokenizer, text: &str) -> Result<Encoding, String> {
+    tokenize_text(tokenizer, text, false, None)?
+        .into_encoding(None, 0, OffsetType::Byte)
+        .map_err(|e| format!("Encoding error: {e}"))
+}
+
+pub fn is_special(tokenizer: &Tokenizer, id: u32) -> bool {
+    tokenizer.get_added_vocabulary().get_added_tokens_decoder().get(&id).is_some_and(|token| token.special)
+}
+
+pub fn is_special_str(tokenizer: &Tokenizer, token: &str) -> bool {
+    tokenizer.get_added_vocabulary().is_special_token(token)
+}
+
+/// Chat template families that wrap each message in extra tokens
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChatFormat {
+    /// `<|start|>{role}<|message|>{content}<|end|>` of OpenAI cl100k/o200k chat models, 3 tokens around each message,
+    /// and 3 more for `<|start|>assistant<|message|>` that primes the reply. Same count as
+    /// `count_tokens_for_messages_openai` with `OpenAiModelFamily::Gpt4`
+    OpenAi,
+    /// `<|start_header_id|>{role}<|end_header_id|>\n\n{content}<|eot_id|>` of Llama 3,
+    /// the conversation starts with `<|begin_of_text|>` and the reply gets an assistant header
+    Llama3,
+}
+
+/// Counts role and text content of each message plus the template overhead, tool calls and images are not counted
+pub fn count_chat_tokens(
+    tokenizer: Option<&Tokenizer>,
+    messages: &[ChatMessage],
+    format: ChatFormat,
+) -> usize {
+    let contents: Vec<String> = messages.iter().map(|message| message.content.content_text_only()).collect();
+    let count = |text: &str| Ok::<_, Infallible>(count_text_tokens_with_fallback(tokenizer, text));
+    match format {
+        ChatFormat::OpenAi => {
+            let messages: Vec<OpenAiMessage> = messages.iter().zip(&contents)
+                .map(|(message, content)| OpenAiMessage { role: &message.role, name: None, content })
+                .collect();
+            let Ok(total) = count_openai_messages(count, OpenAiModelFamily::Gpt4, &messages);
+            total
+        }
+        ChatFormat::Llama3 => {
+            let per_message: usize = messages.iter().zip(&contents)
+                .map(|(message, content)| 4 + count_text_tokens_with_fallback(tokenizer, &message.role)
+                    + count_text_tokens_with_fallback(tokenizer, content))
+                .sum();
+            5 + per_message
+        }
+    }
+}
+
+/// Overheads from OpenAI's "counting tokens for chat completions" cookbook, they changed after `gpt-3.5-turbo-0301`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpenAiModelFamily {
+    /// `gpt-3.5-turbo-0301`: `<|start|>{role/name}\n{content}<|end|>\n`, a name replaces the role
+    Gpt35Turbo0301,
+    /// `gpt-3.5-turbo-0613` and later, `gpt-4*`, `gpt-4o*`
+    Gpt4,
+}
+
+impl OpenAiModelFamily {
+    pub fn from_model_name(model_name: &str) -> Self {
+        if model_name.contains("gpt-3.5-turbo-0301") {
+            OpenAiModelFamily::Gpt35Turbo0301
+        } else {
+            OpenAiModelFamily::Gpt4
+        }
+    }
+
+    fn tokens_per_message(&self) -> i64 {
+        match self {
+            OpenAiModelFamily::Gpt35Turbo0301 => 4,
+            OpenAiModelFamily::Gpt4 => 3,
+        }
+    }
+
+    fn tokens_per_name(&self) -> i64 {
+        match self {
+            OpenAiModelFamily::Gpt35Turbo0301 => -1,
+            OpenAiModelFamily::Gpt4 => 1,
+        }
+    }
+}
+
+/// A chat completions message as the cookbook sees it, `name` is the optional participant name
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpenAiMessage<'a> {
+    pub role: &'a str,
+    pub name: Option<&'a str>,
+    pub content: &'a str,
+}
+
+/// Prompt tokens of a chat completions request the way OpenAI's cookbook counts them: each of role, name
+/// and content encoded separately, plus the per-message and per-name overheads of `family` and 3 tokens
+/// that prime the reply. Needs the model's own tokenizer (cl100k or o200k) to match the API's usage
+pub fn count_tokens_for_messages_openai(
+    counter: &dyn TokenCounter,
+    family: OpenAiModelFamily,
+    messages: &[OpenAiMessage],
+) -> Result<usize, String> {
+    count_openai_messages(|text| counter.count(text), family, messages)
+}
+
+fn count_openai_messages<E>(
+    count: impl Fn(&str) -> Result<usize, E>,
+    family: OpenAiModelFamily,
+    messages: &[OpenAiMessage],
+) -> Result<usize, E> {
+    let mut total: i64 = 3;
+    for message in messages {
+        total += family.tokens_per_message();
+        total += count(message.role)? as i64;
+        total += count(message.content)? as i64;
+        if let Some(name) = message.name {
+            total += family.tokens_per_name();
+            total += count(name)? as i64;
+        }
+    }
+    Ok(total.max(0) as usize)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    const DUMMY_TOKENIZER: &str = include_str!("ast/dummy_tokenizer.json");
+
+    fn dummy_tokenizer() -> Tokenizer {
+        Tokenizer::from_str(DUMMY_TOKENIZER).unwrap()
+    }
+
+    /// Dummy tokenizer with special `<|endoftext|>` (97) and `<|im_start|>` (98), and a non-special `<tab>` (99)
+    fn dummy_tokenizer_with_special_tokens() -> Tokenizer {
+        let mut json: serde_json::Value = serde_json::from_str(DUMMY_TOKENIZER).unwrap();
+        let added_token = |id: u32, content: &str, special: bool| serde_json::json!({
+            "id": id, "content": content, "single_word": false, "lstrip": false, "rstrip": false,
+            "normalized": false, "special": special,
+        });
+        json["added_tokens"] = serde_json::json!([
+            added_token(97, "<|endoftext|>", true),
+            added_token(98, "<|im_start|>", true),
+            added_token(99, "<tab>", false),
+        ]);
+        Tokenizer::from_str(&json.to_string()).unwrap()
+    }
+
+    fn http_response(status: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n", body.len());
+        for (name, value) in headers {
+            response.push_str(&format!("{name}: {value}\r\n"));
+        }
+        response.push_str("\r\n");
+        let mut response = response.into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    fn fast_policy() -> TokenizerDownloadPolicy {
+        TokenizerDownloadPolicy {
+            max_attempts: 2,
+            initial_backoff: Duration::from_millis(1),
+            backoff_multiplier: 2.0,
+            max_backoff: Duration::from_millis(1),
+            max_tokenizer_bytes: DEFAULT_MAX_TOKENIZER_BYTES,
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(10),
+        }
+    }
+
+    fn test_config(cache_dir: &Path, offline: bool) -> TokenizerRegistryConfig {
+        TokenizerRegistryConfig { cache_dir: cache_dir.to_path_buf(), offline, download_policy: fast_policy(), ..Default::default() }
+    }
+
+    /// Loads the way the registry does, without its in-memory cache
+    async fn load_tokenizer(
+        model_rec: &BaseModelRecord,
+        model_id: &str,
+        config: &TokenizerRegistryConfig,
+        metrics: &TokenizerMetrics,
+    ) -> Result<Option<Arc<Tokenizer>>, TokenizerError> {
+        let http_client = reqwest::Client::new();
+        let cx = TokenizerLoadContext { config, http_client: &http_client, hf_tokenizer_template: "", metrics, progress: None, cancel: None };
+        load_tokenizer_and_source(&cx, model_rec, model_id).await.map(|(tokenizer, _)| tokenizer)
+    }
+
+    /// Serves every request with `handler(raw_request_head)`, returns the base url
+    async fn spawn_http_server<F>(handler: F) -> String
+    where
+        F: Fn(&str) -> Vec<u8> + Send + Sync + 'static,
+    {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = Arc::new(handler);
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    let mut buf = vec![0u8; 16384];
+                    let n = socket.read(&mut buf).await.unwrap_or(0);
+                    let response = handler(&String::from_utf8_lossy(&buf[..n]));
+                    let _ = socket.write_all(&response).await;
+                    let _ = socket.shutdown().await;
+                });
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    #[test]
+    fn test_encode_batch_matches_encode_fast() {
+        let tokenizer = dummy_tokenizer();
+        let texts = ["def f(x):", "", "return x**2", "hello world"];
+        let batch = encode_batch(&tokenizer, &texts, false).unwrap();
+        assert_eq!(batch.len(), texts.len());
+        for (text, encoding) in texts.iter().zip(batch.iter()) {
+            let single = tokenizer.encode_fast(*text, false).unwrap();
+            assert_eq!(encoding.get_ids(), single.get_ids());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_rejects_checksum_mismatch() {
+        let base_url = spawn_http_server(|_| http_response("200 OK", &[], DUMMY_TOKENIZER.as_bytes())).await;
+        let cache_dir = tempfile::tempdir().unwrap();
+        let path = cache_dir.path().join("model").join("tokenizer.json");
+        let wrong_sha256 = "0".repeat(64);
+        let err = try_download_tokenizer_file_and_open(
+            &reqwest::Client::new(), &format!("{base_url}/tokenizer.json"), "", &path, Some(&wrong_sha256), false, &fast_policy(), None, None, None,
+        ).await.unwrap_err();
+        assert!(err.to_string().contains("checksum"), "{err}");
+        assert!(!path.exists());
+
+        let mut hasher = Sha256::new();
+        hasher.update(DUMMY_TOKENIZER.as_bytes());
+        let right_sha256 = format!("{:x}", hasher.finalize());
+        try_download_tokenizer_file_and_open(
+            &reqwest::Client::new(), &format!("{base_url}/tokenizer.json"), "", &path, Some(&right_sha256), false, &fast_policy(), None, None, None,
+        ).await.unwrap();
+        assert!(path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_download_reuses_cached_file_on_not_modified() {
+        let full_downloads = Arc::new(AtomicUsize::new(0));
+        let full_downloads_server = full_downloads.clone();
+        let base_url = spawn_http_server(move |request| {
+            if request.to_lowercase().contains("if-none-match: \"v1\"") {
+                http_response("304 Not Modified", &[("ETag", "\"v1\"")], b"")
+            } else {
+                full_downloads_server.fetch_add(1, Ordering::SeqCst);
+                http_response("200 OK", &[("ETag", "\"v1\"")], DUMMY_TOKENIZER.as_bytes())
+            }
+        }).await;
+        let url = format!("{base_url}/tokenizer.json");
+        let dir = tempfile::tempdir().unwrap();
+        let cached_path = dir.path().join("tokenizer.json");
+
+        let first_tmp = dir.path().join("first.tmp");
+        let etag = download_tokenizer_file(&reqwest::Client::new(), &url, "", &first_tmp, &cached_path, None, None, &TokenizerDownloadPolicy::default()).await.unwrap();
+        assert_eq!(etag.as_deref(), Some("\"v1\""));
+        tokio::fs::rename(&first_tmp, &cached_path).await.unwrap();
+        tokio::fs::write(etag_path(&cached_path), etag.unwrap()).await.unwrap();
+
+        let second_tmp = dir.path().join("second.tmp");
+        let etag = download_tokenizer_file(&reqwest::Client::new(), &url, "", &second_tmp, &cached_path, None, None, &TokenizerDownloadPolicy::default()).await.unwrap();
+        assert_eq!(etag.as_deref(), Some("\"v1\""));
+        assert_eq!(tokio::fs::read_to_string(&second_tmp).await.unwrap(), DUMMY_TOKENIZER);
+        assert_eq!(full_downloads.load(Ordering::SeqCst), 1);
+
+        tokio::fs::write(&cached_path, "{\"version\": ").await.unwrap();
+        let third_tmp = dir.path().join("third.tmp");
+        download_tokenizer_file(&reqwest::Client::new(), &url, "", &third_tmp, &cached_path, None, None, &TokenizerDownloadPolicy::default()).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(&third_tmp).await.unwrap(), DUMMY_TOKENIZER);
+        assert_eq!(full_downloads.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_offline_mode_never_downloads() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_server = requests.clone();
+        let base_url = spawn_http_server(move |_| {
+            requests_server.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", &[], DUMMY_TOKENIZER.as_bytes())
+        }).await;
+        let url = format!("{base_url}/tokenizer.json");
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model").join("tokenizer.json");
+
+        let err = try_download_tokenizer_file_and_open(&reqwest::Client::new(), &url, "", &path, None, true, &fast_policy(), None, None, None)
+            .await.unwrap_err();
+        assert!(matches!(err, TokenizerError::NotFound(_)), "{err}");
+
+        tokio::fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+        tokio::fs::write(&path, DUMMY_TOKENIZER).await.unwrap();
+        try_download_tokenizer_file_and_open(&reqwest::Client::new(), &url, "", &path, None, true, &fast_policy(), None, None, None)
+            .await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_download_policy_limits_attempts() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_server = requests.clone();
+        let base_url = spawn_http_server(move |_| {
+            requests_server.fetch_add(1, Ordering::SeqCst);
+            http_response("500 Internal Server Error", &[], b"")
+        }).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model").join("tokenizer.json");
+        let err = try_download_tokenizer_file_and_open(
+            &reqwest::Client::new(), &format!("{base_url}/tokenizer.json"), "", &path, None, false, &fast_policy(), None, None, None,
+        ).await.unwrap_err();
+        assert!(matches!(err, TokenizerError::Download(_)), "{err}");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_download_policy_backoff() {
+        let policy = TokenizerDownloadPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            backoff_multiplier: 2.0,
+            max_backoff: Duration::from_millis(300),
+            ..Default::default()
+        };
+        for (retry, expected_ms) in [(1, 100), (2, 200), (3, 300), (4, 300)] {
+            let backoff = policy.backoff(retry);
+            assert!(backoff <= Duration::from_millis(expected_ms), "retry {retry}: {backoff:?}");
+            assert!(backoff >= Duration::from_millis(expected_ms / 2), "retry {retry}: {backoff:?}");
+        }
+    }
+
+    #[test]
+    fn test_tokenizer_cache_evicts_least_recently_used() {
+        let tokenizer = Some(Arc::new(dummy_tokenizer()));
+        let mut cache = TokenizerCache::new(2);
+        cache.insert("model-a".to_string(), tokenizer.clone());
+        cache.insert("model-b".to_string(), tokenizer.clone());
+        assert!(cache.get("model-a").is_some());
+        cache.insert("model-c".to_string(), None);
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains_key("model-b"));
+        assert!(cache.get("model-b").is_none());
+
+        // reloading an evicted model pushes out the next least recently used one
+        cache.insert("model-b".to_string(), tokenizer.clone());
+        assert!(!cache.contains_key("model-a"));
+        assert!(cache.get("model-c").unwrap().is_none());
+        assert!(cache.get("model-b").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_count_chat_tokens() {
+        let tokenizer = dummy_tokenizer();
+        let tokenizer = Some(&tokenizer);
+        let messages = vec![
+            ChatMessage::new("system".to_string(), "You are helpful.".to_string()),
+            ChatMessage::new("user".to_string(), "Hi".to_string()),
+        ];
+        // dummy tokenizer has one token per char, so the content is 6+16 and 4+2 tokens
+        let content_tokens = 6 + 16 + 4 + 2;
+        assert_eq!(count_chat_tokens(tokenizer, &messages, ChatFormat::OpenAi), 3 + 2 * 3 + content_tokens);
+        assert_eq!(count_chat_tokens(tokenizer, &messages, ChatFormat::Llama3), 5 + 2 * 4 + content_tokens);
+        assert_eq!(count_chat_tokens(tokenizer, &[], ChatFormat::OpenAi), 3);
+    }
+
+    #[tokio::test]
+    async fn test_async_encoding_matches_sync() {
+        let tokenizer = Arc::new(dummy_tokenizer());
+        let text = "def f(x):\n    return x**2\n".repeat(2000);
+        assert!(text.len() > ASYNC_ENCODE_THRESHOLD_BYTES);
+        let count = count_text_tokens_async(Some(tokenizer.clone()), text.clone()).await.unwrap();
+        assert_eq!(count, count_text_tokens(Some(&tokenizer), &text).unwrap());
+        let encoding = encode_fast_async(tokenizer.clone(), text.clone(), false).await.unwrap();
+        assert_eq!(encoding.get_ids().len(), count);
+    }
+
+    #[test]
+    fn test_tokenizer_from_bytes() {
+        let tokenizer = tokenizer_from_bytes(DUMMY_TOKENIZER.as_bytes()).unwrap();
+        assert_eq!(count_text_tokens(Some(&tokenizer), "hello").unwrap(), 5);
+        let err = tokenizer_from_bytes(b"<html>not a tokenizer</html>").unwrap_err();
+        assert!(matches!(err, TokenizerError::Parse(_)), "{err}");
+    }
+
+    #[test]
+    fn test_encode_with_overflow_counts_dropped_tokens() {
+        let mut tokenizer = dummy_tokenizer();
+        let (encoding, dropped) = encode_with_overflow(&tokenizer, "abcdefghij", false).unwrap();
+        assert_eq!((encoding.len(), dropped), (10, 0));
+        for stride in [0, 1, 3] {
+            tokenizer.with_truncation(Some(tokenizers::TruncationParams {
+                max_length: 4,
+                stride,
+                ..Default::default()
+            })).unwrap();
+            let (encoding, dropped) = encode_with_overflow(&tokenizer, "abcdefghij", false).unwrap();
+            assert_eq!((encoding.len(), dropped), (4, 6), "stride {stride}");
+        }
+    }
+
+    #[test]
+    fn test_special_tokens() {
+        let tokenizer = dummy_tokenizer_with_special_tokens();
+        let special = special_tokens(&tokenizer);
+        assert_eq!(special, vec![("<|endoftext|>".to_string(), 97), ("<|im_start|>".to_string(), 98)]);
+        for (content, id) in special {
+            assert_eq!(tokenizer.token_to_id(&content), Some(id));
+            assert_eq!(decode_tokens(&tokenizer, &[id], false).unwrap(), content);
+        }
+        assert!(special_tokens(&dummy_tokenizer()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_download_reports_progress() {
+        let base_url = spawn_http_server(|_| http_response("200 OK", &[], DUMMY_TOKENIZER.as_bytes())).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model").join("tokenizer.json");
+        let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let reports_cb = reports.clone();
+        let progress: TokenizerDownloadProgress = Arc::new(move |downloaded, total| {
+            reports_cb.lock().unwrap().push((downloaded, total));
+        });
+        try_download_tokenizer_file_and_open(
+            &reqwest::Client::new(), &format!("{base_url}/tokenizer.json"), "", &path, None, false, &fast_policy(), None, Some(&progress), None,
+        ).await.unwrap();
+        let reports = reports.lock().unwrap();
+        let total = DUMMY_TOKENIZER.len() as u64;
+        assert!(!reports.is_empty());
+        assert!(reports.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(reports.last(), Some(&(total, Some(total))));
+    }
+
+    #[tokio::test]
+    async fn test_download_locks_are_per_model() {
+        let locks = TokenizerDownloadLocks::default();
+        let model_a = locks.lock_for("model-a");
+        let _model_a_locked = model_a.lock().await;
+        assert!(locks.lock_for("model-a").try_lock().is_err());
+
+        let model_b = locks.lock_for("model-b");
+        let other_download = tokio::spawn(async move {
+            let _model_b_locked = model_b.lock().await;
+        });
+        tokio::time::timeout(Duration::from_secs(5), other_download).await
+            .expect("model-b download was blocked by model-a").unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_load_tokenizer_once() {
+        let tokenizer_map = AMutex::new(TokenizerCache::new(TOKENIZER_CACHE_CAPACITY));
+        let download_locks = TokenizerDownloadLocks::default();
+        let metrics = TokenizerMetrics::default();
+        let loads = AtomicUsize::new(0);
+        let load = || async {
+            loads.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(Some(Arc::new(dummy_tokenizer())))
+        };
+        let (first, second) = tokio::join!(
+            get_or_load_tokenizer(&tokenizer_map, &download_locks, &metrics, "model", load),
+            get_or_load_tokenizer(&tokenizer_map, &download_locks, &metrics, "model", load),
+        );
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert!(Arc::ptr_eq(&first.unwrap().unwrap(), &second.unwrap().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_fake_tokenizer_is_cached() {
+        let tokenizer_map = AMutex::new(TokenizerCache::new(TOKENIZER_CACHE_CAPACITY));
+        let download_locks = TokenizerDownloadLocks::default();
+        let metrics = TokenizerMetrics::default();
+        let loads = AtomicUsize::new(0);
+        let load = || async {
+            loads.fetch_add(1, Ordering::SeqCst);
+            Ok(None)
+        };
+        for _ in 0..3 {
+            let tokenizer = get_or_load_tokenizer(&tokenizer_map, &download_locks, &metrics, "fake-model", load).await.unwrap();
+            assert!(tokenizer.is_none());
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert!(tokenizer_map.lock().await.contains_key("fake-model"));
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_tokenizer() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let tokenizer_map = AMutex::new(TokenizerCache::new(TOKENIZER_CACHE_CAPACITY));
+        tokenizer_map.lock().await.insert("org/model".to_string(), Some(Arc::new(dummy_tokenizer())));
+        let path = tokenizer_cache_path(cache_dir.path(), "org/model");
+        tokio::fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+        tokio::fs::write(&path, DUMMY_TOKENIZER).await.unwrap();
+        tokio::fs::write(etag_path(&path), "\"v1\"").await.unwrap();
+
+        invalidate_cached_tokenizer(&tokenizer_map, cache_dir.path(), "org/model").await.unwrap();
+        assert!(!tokenizer_map.lock().await.contains_key("org/model"));
+        assert!(!path.parent().unwrap().exists());
+        invalidate_cached_tokenizer(&tokenizer_map, cache_dir.path(), "org/model").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_download_rejects_tokenizer_that_encodes_nothing() {
+        const EMPTY_VOCAB_TOKENIZER: &str = r#"{"version": "1.0", "model": {"type": "BPE", "vocab": {}, "merges": []}}"#;
+        let base_url = spawn_http_server(|_| http_response("200 OK", &[], EMPTY_VOCAB_TOKENIZER.as_bytes())).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model").join("tokenizer.json");
+        let err = try_download_tokenizer_file_and_open(
+            &reqwest::Client::new(), &format!("{base_url}/tokenizer.json"), "", &path, None, false, &fast_policy(), None, None, None,
+        ).await.unwrap_err();
+        assert!(matches!(err, TokenizerError::Parse(_)), "{err}");
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_download_rejects_html_pages_without_retrying() {
+        const LOGIN_PAGE: &[u8] = b"\n<!DOCTYPE html><html><body>Please log in</body></html>";
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_server = requests.clone();
+        let base_url = spawn_http_server(move |request| {
+            requests_server.fetch_add(1, Ordering::SeqCst);
+            if request.starts_with("GET /typed") {
+                http_response("200 OK", &[("Content-Type", "text/html; charset=utf-8")], LOGIN_PAGE)
+            } else {
+                http_response("200 OK", &[("Content-Type", "application/json")], LOGIN_PAGE)
+            }
+        }).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model").join("tokenizer.json");
+        for url in [format!("{base_url}/typed/tokenizer.json"), format!("{base_url}/sniffed/tokenizer.json")] {
+            let err = try_download_tokenizer_file_and_open(
+                &reqwest::Client::new(), &url, "", &path, None, false, &fast_policy(), None, None, None,
+            ).await.unwrap_err();
+            assert!(matches!(err, TokenizerError::HtmlResponse(_)), "{err}");
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_tokenizer_auth_header() {
+        assert_eq!(tokenizer_auth_header("").unwrap(), None);
+        assert_eq!(tokenizer_auth_header("secret").unwrap(), Some((AUTHORIZATION, "Bearer secret".to_string())));
+        assert_eq!(
+            tokenizer_auth_header("Basic dXNlcjpwYXNz").unwrap(),
+            Some((AUTHORIZATION, "Basic dXNlcjpwYXNz".to_string())),
+        );
+        assert_eq!(
+            tokenizer_auth_header("Header:X-Api-Key=secret").unwrap(),
+            Some((HeaderName::from_static("x-api-key"), "secret".to_string())),
+        );
+        assert!(tokenizer_auth_header("Header:X-Api-Key").is_err());
+        assert!(tokenizer_auth_header("Header:bad name=secret").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_download_through_tokenizer_proxy() {
+        let proxy_url = spawn_http_server(|request| {
+            if request.starts_with("GET http://tokenizers.invalid/tokenizer.json") {
+                http_response("200 OK", &[], DUMMY_TOKENIZER.as_bytes())
+            } else {
+                http_response("502 Bad Gateway", &[], b"")
+            }
+        }).await;
+        let client = build_tokenizer_http_client(Some(&proxy_url), None, DEFAULT_TOKENIZER_CONNECT_TIMEOUT, false).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model").join("tokenizer.json");
+        try_download_tokenizer_file_and_open(
+            &client, "http://tokenizers.invalid/tokenizer.json", "", &path, None, false, &fast_policy(), None, None, None,
+        ).await.unwrap();
+        assert!(path.exists());
+
+        let bad_cert = dir.path().join("ca.pem");
+        std::fs::write(&bad_cert, "not a certificate").unwrap();
+        assert!(build_tokenizer_http_client(None, Some(&bad_cert), DEFAULT_TOKENIZER_CONNECT_TIMEOUT, false).is_err());
+        assert!(build_tokenizer_http_client(None, Some(&dir.path().join("missing.pem")), DEFAULT_TOKENIZER_CONNECT_TIMEOUT, false).is_err());
+    }
+
+    #[test]
+    fn test_hf_tokenizer_url() {
+        let template = default_hf_tokenizer_template();
+        assert_eq!(
+            hf_tokenizer_url(&template, "org/model", None, None),
+            "https://huggingface.co/org/model/resolve/main/tokenizer.json",
+        );
+        assert_eq!(
+            hf_tokenizer_url(&template, "org/model", Some("abc123"), Some("tok/tokenizer.json")),
+            "https://huggingface.co/org/model/resolve/abc123/tok/tokenizer.json",
+        );
+        assert_eq!(
+            hf_tokenizer_url("https://mirror/$HF_MODEL/tokenizer.json", "org/model", Some("abc123"), None),
+            "https://mirror/org/model/tokenizer.json",
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_rejects_oversized_tokenizer() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_server = requests.clone();
+        let base_url = spawn_http_server(move |_| {
+            requests_server.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", &[], DUMMY_TOKENIZER.as_bytes())
+        }).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model").join("tokenizer.json");
+        let policy = TokenizerDownloadPolicy { max_tokenizer_bytes: 1024, ..fast_policy() };
+        let err = try_download_tokenizer_file_and_open(
+            &reqwest::Client::new(), &format!("{base_url}/tokenizer.json"), "", &path, None, false, &policy, None, None, None,
+        ).await.unwrap_err();
+        assert!(matches!(err, TokenizerError::TooLarge(_)), "{err}");
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert!(!path.exists());
+
+        // no Content-Length, the limit is enforced while streaming
+        let tmp_path = dir.path().join("streamed.json");
+        let body = DUMMY_TOKENIZER.as_bytes().to_vec();
+        let chunked_url = spawn_http_server(move |_| {
+            let mut response = b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n".to_vec();
+            response.extend_from_slice(&body);
+            response
+        }).await;
+        let res = reqwest::get(format!("{chunked_url}/tokenizer.json")).await.unwrap();
+        assert_eq!(res.content_length(), None);
+        let err = try_open_tokenizer(res, &tmp_path, None, 1024, 0).await.unwrap_err();
+        assert!(matches!(err, TokenizerError::TooLarge(_)), "{err}");
+    }
+
+    #[test]
+    fn test_id_to_token_round_trip() {
+        for tokenizer in [dummy_tokenizer(), dummy_tokenizer_with_special_tokens()] {
+            for id in [0, 1, 42, 96] {
+                let token = tokenizer.id_to_token(id).unwrap();
+                assert_eq!(tokenizer.token_to_id(&token), Some(id));
+            }
+            assert_eq!(tokenizer.id_to_token(100_000), None);
+            assert_eq!(tokenizer.token_to_id("no such token"), None);
+        }
+        let tokenizer = dummy_tokenizer_with_special_tokens();
+        assert_eq!(tokenizer.id_to_token(98).as_deref(), Some("<|im_start|>"));
+        assert_eq!(tokenizer.token_to_id("<|im_start|>"), Some(98));
+    }
+
+    #[test]
+    fn test_count_tokens_matches_encode_fast() {
+        let mut with_post_processor = dummy_tokenizer_with_special_tokens();
+        let template = tokenizers::processors::template::TemplateProcessing::builder()
+            .try_single("<|im_start|> $A <|endoftext|>").unwrap()
+            .special_tokens(vec![("<|im_start|>", 98), ("<|endoftext|>", 97)])
+            .build().unwrap();
+        with_post_processor.with_post_processor(Some(template));
+        let texts = ["", "hello world", "fn main() {\n    println!(\"hi\");\n}", "<|im_start|>user<|endoftext|>", "\u{444}\u{444}"];
+        for tokenizer in [dummy_tokenizer(), dummy_tokenizer_with_special_tokens(), with_post_processor] {
+            for text in texts {
+                for add_special in [false, true] {
+                    let expected = tokenizer.encode_fast(text, add_special).unwrap().len();
+                    assert_eq!(count_tokens(&tokenizer, text, add_special).unwrap(), expected, "{text:?} {add_special}");
+                }
+            }
+        }
+
+        let mut padded = dummy_tokenizer();
+        padded.with_padding(Some(PaddingParams { strategy: PaddingStrategy::Fixed(64), ..Default::default() }));
+        assert_eq!(padded.encode_fast("hello", false).unwrap().len(), 64);
+        assert_eq!(count_tokens(&padded, "hello", false).unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_model_max_length_does_not_cap_token_counts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokenizer.json");
+        std::fs::write(&path, DUMMY_TOKENIZER).unwrap();
+        std::fs::write(dir.path().join("tokenizer_config.json"), r#"{"model_max_length": 4}"#).unwrap();
+        let model_rec = BaseModelRecord { id: "model".to_string(), tokenizer: path.display().to_string(), ..Default::default() };
+        let tokenizer = load_tokenizer(&model_rec, "model", &test_config(dir.path(), true), &TokenizerMetrics::default()).await.unwrap().unwrap();
+
+        let text = "abcdefghij";
+        assert!(tokenizer.get_truncation().is_none());
+        assert_eq!(count_tokens(&tokenizer, text, false).unwrap(), 10);
+        assert_eq!(count_text_tokens(Some(&tokenizer), text).unwrap(), 10);
+        let mut counter = IncrementalCounter::new(Some(&tokenizer));
+        assert_eq!(counter.append(text).unwrap(), 10);
+    }
+
+    #[test]
+    fn test_truncation_and_padding_are_validated() {
+        let mut tokenizer = dummy_tokenizer();
+        assert!(set_truncation(&mut tokenizer, Some(TruncationParams { max_length: 0, ..Default::default() })).is_err());
+        assert!(set_truncation(&mut tokenizer, Some(TruncationParams { max_length: 4, stride: 4, ..Default::default() })).is_err());
+        assert!(tokenizer.get_truncation().is_none());
+        set_truncation(&mut tokenizer, Some(TruncationParams { max_length: 4, ..Default::default() })).unwrap();
+        assert_eq!(tokenizer.encode_fast("abcdefgh", false).unwrap().len(), 4);
+        set_truncation(&mut tokenizer, None).unwrap();
+        assert_eq!(tokenizer.encode_fast("abcdefgh", false).unwrap().len(), 8);
+
+        assert!(set_padding(&mut tokenizer, Some(PaddingParams { strategy: PaddingStrategy::Fixed(0), ..Default::default() })).is_err());
+        assert!(set_padding(&mut tokenizer, Some(PaddingParams { pad_to_multiple_of: Some(0), ..Default::default() })).is_err());
+        assert!(tokenizer.get_padding().is_none());
+        set_padding(&mut tokenizer, Some(PaddingParams { strategy: PaddingStrategy::Fixed(10), ..Default::default() })).unwrap();
+        assert_eq!(tokenizer.encode_fast("abc", false).unwrap().len(), 10);
+    }
+
+    #[test]
+    fn test_incremental_counter_matches_full_count() {
+        let chunks = [
+            "user: please look at src/main.rs\n",
+            "tool: ",
+            "fn main() {\n    let x",
+            " = 42;\n    println!(\"{x}\");\n}\n",
+            "assistant: the answer is 42",
+            "",
+            "\n",
+        ];
+        let tokenizer = dummy_tokenizer();
+        let mut counter = IncrementalCounter::new(Some(&tokenizer));
+        let mut full_text = String::new();
+        for chunk in chunks {
+            full_text.push_str(chunk);
+            let expected = count_tokens(&tokenizer, &full_text, false).unwrap();
+            assert_eq!(counter.append(chunk).unwrap(), expected, "{full_text:?}");
+        }
+
+        let mut counter = IncrementalCounter::new(None);
+        for chunk in chunks {
+            counter.append(chunk).unwrap();
+        }
+        assert_eq!(counter.append("").unwrap(), estimate_tokens(&full_text));
+    }
+
+    #[test]
+    fn test_encode_with_special_policy() {
+        let tokenizer = dummy_tokenizer_with_special_tokens();
+        let text = "a<|endoftext|>b";
+        let allowed = HashSet::from(["<|endoftext|>".to_string()]);
+        let special = encode_with_special_policy(&tokenizer, text, &allowed).unwrap();
+        assert_eq!(special.get_ids().len(), 3);
+        assert_eq!(special.get_ids()[1], 97);
+        assert_eq!(special.get_offsets(), &[(0, 1), (1, 14), (14, 15)]);
+
+        let literal = encode_with_special_policy(&tokenizer, text, &HashSet::new()).unwrap();
+        assert_eq!(literal.get_ids().len(), text.len());
+        assert!(!literal.get_ids().contains(&97));
+        assert_eq!(decode_tokens(&tokenizer, literal.get_ids(), false).unwrap(), text);
+        assert_eq!(literal.get_offsets().last(), Some(&(14, 15)));
+    }
+
+    #[test]
+    fn test_describe() {
+        let info = describe(&dummy_tokenizer_with_special_tokens(), "hf://org/model");
+        assert_eq!(info, TokenizerInfo { backend: "BPE".to_string(), model_name: "hf://org/model".to_string(), vocab_size: 100 });
+        assert_eq!(info.to_string(), "BPE tokenizer \"hf://org/model\", vocab size 100");
+    }
+
+    #[test]
+    fn test_repeated_errors_are_logged_rarely() {
+        let seen = StdMutex::new(HashMap::new());
+        let logged: Vec<usize> = (0..1000)
+            .filter_map(|_| count_repeated_error(&seen, "Encoding error: broken"))
+            .collect();
+        assert_eq!(logged, vec![1, 10, 100, 1000]);
+        assert_eq!(count_repeated_error(&seen, "Encoding error: other"), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_download_resumes_with_range_requests() {
+        let body = DUMMY_TOKENIZER.as_bytes();
+        let half = body.len() / 2;
+        let ranges = Arc::new(StdMutex::new(Vec::new()));
+        let ranges_server = ranges.clone();
+        let base_url = spawn_http_server(move |request| {
+            let header = |name: &str| request.lines()
+                .find_map(|line| line.to_ascii_lowercase().strip_prefix(name).map(|v| v.trim().to_string()));
+            let range = header("range: bytes=").map(|r| r.trim_end_matches('-').to_string());
+            ranges_server.lock().unwrap().push((range.clone(), header("if-range:")));
+            match range.and_then(|r| r.parse::<usize>().ok()) {
+                Some(start) => {
+                    let content_range = format!("bytes {}-{}/{}", start, body.len() - 1, body.len());
+                    http_response("206 Partial Content", &[("Content-Range", &content_range)], &body[start..])
+                }
+                None => {
+                    // the connection drops in the middle of the body
+                    let mut response = http_response("200 OK", &[("ETag", "\"v1\"")], body);
+                    response.truncate(response.len() - (body.len() - half));
+                    response
+                }
+            }
+        }).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model").join("tokenizer.json");
+        let mut hasher = Sha256::new();
+        hasher.update(body);
+        let sha256 = format!("{:x}", hasher.finalize());
+        try_download_tokenizer_file_and_open(
+            &reqwest::Client::new(), &format!("{base_url}/tokenizer.json"), "", &path, Some(&sha256), false, &fast_policy(), None, None, None,
+        ).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        assert_eq!(*ranges.lock().unwrap(), vec![(None, None), (Some(half.to_string()), Some("\"v1\"".to_string()))]);
+    }
+
+    #[tokio::test]
+    async fn test_download_restarts_when_resumed_at_the_wrong_byte() {
+        let body = DUMMY_TOKENIZER.as_bytes();
+        let half = body.len() / 2;
+        let ranges = Arc::new(StdMutex::new(Vec::new()));
+        let ranges_server = ranges.clone();
+        let base_url = spawn_http_server(move |request| {
+            let range = request.lines()
+                .find_map(|line| line.to_ascii_lowercase().strip_prefix("range: bytes=").map(|r| r.trim_end_matches('-').to_string()));
+            let mut ranges = ranges_server.lock().unwrap();
+            ranges.push(range.clone());
+            match (range, ranges.len()) {
+                // a byte earlier than asked for
+                (Some(_), _) => {
+                    let content_range = format!("bytes {}-{}/{}", half - 1, body.len() - 1, body.len());
+                    http_response("206 Partial Content", &[("Content-Range", &content_range)], &body[half - 1..])
+                }
+                (None, 1) => {
+                    let mut response = http_response("200 OK", &[("Last-Modified", "Wed, 21 Oct 2015 07:28:00 GMT")], body);
+                    response.truncate(response.len() - (body.len() - half));
+                    response
+                }
+                (None, _) => http_response("200 OK", &[], body),
+            }
+        }).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model").join("tokenizer.json");
+        let policy = TokenizerDownloadPolicy { max_attempts: 3, ..fast_policy() };
+        try_download_tokenizer_file_and_open(
+            &reqwest::Client::new(), &format!("{base_url}/tokenizer.json"), "", &path, None, false, &policy, None, None, None,
+        ).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        assert_eq!(*ranges.lock().unwrap(), vec![None, Some(half.to_string()), None]);
+
+        // without a validator the partial file can't be checked, so it's not resumed
+        let to = dir.path().join("no-validator.tmp");
+        std::fs::write(&to, &body[..half]).unwrap();
+        download_tokenizer_file(
+            &reqwest::Client::new(), &format!("{base_url}/tokenizer.json"), "", &to, &dir.path().join("cached.json"), None, None, &fast_policy(),
+        ).await.unwrap();
+        assert_eq!(std::fs::read(&to).unwrap(), body);
+        assert_eq!(ranges.lock().unwrap().last(), Some(&None));
+    }
+
+    #[tokio::test]
+    async fn test_ignored_range_request_is_not_counted_twice() {
+        let body = DUMMY_TOKENIZER.as_bytes();
+        let base_url = spawn_http_server(move |_| http_response("200 OK", &[], body)).await;
+        let dir = tempfile::tempdir().unwrap();
+        let to = dir.path().join("tokenizer.json.tmp");
+        std::fs::write(&to, &body[..body.len() / 2]).unwrap();
+        std::fs::write(if_range_path(&to), "\"v1\"").unwrap();
+        let policy = TokenizerDownloadPolicy { max_tokenizer_bytes: body.len() as u64, ..fast_policy() };
+        download_tokenizer_file(
+            &reqwest::Client::new(), &format!("{base_url}/tokenizer.json"), "", &to, &dir.path().join("cached.json"), None, None, &policy,
+        ).await.unwrap();
+        assert_eq!(std::fs::read(&to).unwrap(), body);
+    }
+
+    #[test]
+    fn test_list_cached_tokenizers() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(list_cached_tokenizers(dir.path()).is_empty());
+        for model_id in ["org/model-b", "org/model-a"] {
+            let path = tokenizer_cache_path(dir.path(), model_id);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, DUMMY_TOKENIZER).unwrap();
+        }
+        std::fs::create_dir_all(dir.path().join("tokenizers").join("half_downloaded")).unwrap();
+        let entries = list_cached_tokenizers(dir.path());
+        assert_eq!(entries.iter().map(|e| e.model_id.as_str()).collect::<Vec<_>>(), vec!["org_model_a", "org_model_b"]);
+        assert_eq!(entries[0].path, tokenizer_cache_path(dir.path(), "org/model-a"));
+        assert_eq!(entries[0].size, DUMMY_TOKENIZER.len() as u64);
+        assert!(entries[0].modified.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_prune_tokenizer_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = SystemTime::now();
+        for (age_secs, model_id) in [(300, "org/oldest"), (200, "org/loaded"), (100, "org/older"), (0, "org/newest")] {
+            let path = tokenizer_cache_path(dir.path(), model_id);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, vec![b'x'; 1000]).unwrap();
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(now - Duration::from_secs(age_secs)).unwrap();
+        }
+        assert_eq!(tokenizer_cache_size(dir.path()), 4000);
+
+        let in_use = HashSet::from([sanitize_model_id("org/loaded")]);
+        let freed = prune_tokenizer_cache(dir.path(), 2500, &in_use).await;
+        assert_eq!(freed, 2000);
+        assert_eq!(tokenizer_cache_size(dir.path()), 2000);
+        let left: Vec<String> = list_cached_tokenizers(dir.path()).into_iter().map(|e| e.model_id).collect();
+        assert_eq!(left, vec!["org_loaded", "org_newest"]);
+
+        assert_eq!(prune_tokenizer_cache(dir.path(), 2500, &in_use).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_list_and_prune_candidate_tokenizers() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = SystemTime::now();
+        let set_modified = |path: &Path, age_secs: u64| {
+            std::fs::File::open(path).unwrap().set_modified(now - Duration::from_secs(age_secs)).unwrap();
+        };
+        let candidate_path = tokenizer_candidate_cache_path(dir.path(), "org/candidates", "https://example.com/tokenizer.json");
+        let plain_path = tokenizer_cache_path(dir.path(), "org/plain");
+        for path in [&candidate_path, &plain_path] {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, vec![b'x'; 1000]).unwrap();
+        }
+        // the candidate was used last, its model directory itself is older than everything
+        set_modified(&candidate_path, 0);
+        set_modified(&plain_path, 100);
+        set_modified(candidate_path.parent().unwrap().parent().unwrap(), 1000);
+
+        let entries = list_cached_tokenizers(dir.path());
+        assert_eq!(entries.iter().map(|e| e.model_id.as_str()).collect::<Vec<_>>(), vec!["org_candidates", "org_plain"]);
+        assert_eq!(entries[0].path, candidate_path);
+        assert_eq!(entries[0].size, 1000);
+
+        assert_eq!(prune_tokenizer_cache(dir.path(), 1500, &HashSet::new()).await, 1000);
+        assert!(candidate_path.exists());
+        assert!(!plain_path.exists());
+        assert_eq!(prune_tokenizer_cache(dir.path(), 500, &HashSet::new()).await, 1000);
+        assert!(list_cached_tokenizers(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn test_count_text_tokens_with_mock() {
+        use crate::tokens::test_support::MockTokenizer;
+        let text = "  def  main():\n    return 42\n";
+        assert_eq!(count_text_tokens_with(Some(&MockTokenizer), text), Ok(4));
+        assert_eq!(count_text_tokens_with(None, text), Ok(estimate_tokens(text)));
+        let encoding = MockTokenizer.encode(text, false).unwrap();
+        assert_eq!(encoding.get_tokens(), &["def", "main():", "return", "42"]);
+        assert_eq!(encoding.get_offsets()[1], (7, 14));
+
+        let tokenizer = Arc::new(dummy_tokenizer());
+        let counter: &dyn TokenCounter = tokenizer.as_ref();
+        assert_eq!(count_text_tokens_with(Some(counter), "hello"), count_text_tokens(Some(&tokenizer), "hello"));
+        assert_eq!(counter.encode("hello", false).unwrap().get_ids().len(), 5);
+    }
+
+    #[test]
+    fn test_resolve_tokenizer_plan() {
+        let cache_dir = PathBuf::from("/cache");
+        let template = "https://huggingface.co/$HF_MODEL/resolve/$HF_REVISION/$HF_FILENAME";
+        let plan_for = |tokenizer: &str| {
+            let model_rec = BaseModelRecord { id: "org/model".to_string(), tokenizer: tokenizer.to_string(), ..Default::default() };
+            resolve_tokenizer_plan(&model_rec, &cache_dir, None, template)
+        };
+        let cached = Some(tokenizer_cache_path(&cache_dir, "org/model"));
+
+        let plan = plan_for("").unwrap();
+        assert_eq!((plan.source, plan.url, plan.path, plan.format), (TokenizerSource::Empty, None, None, None));
+        assert_eq!(plan_for("fake").unwrap().source, TokenizerSource::Fake);
+
+        let plan = plan_for("hf://org/tokenizer-repo").unwrap();
+        assert_eq!(plan.source, TokenizerSource::Hf("org/tokenizer-repo".to_string()));
+        assert_eq!(plan.url.as_deref(), Some("https://huggingface.co/org/tokenizer-repo/resolve/main/tokenizer.json"));
+        assert_eq!((plan.path, plan.format), (cached.clone(), Some(TokenizerFileFormat::Json)));
+
+        let plan = plan_for("https://example.com/tokenizer.json").unwrap();
+        assert_eq!(plan.source, TokenizerSource::Http("https://example.com/tokenizer.json".to_string()));
+        assert_eq!(plan.url.as_deref(), Some("https://example.com/tokenizer.json"));
+        assert_eq!(plan.path, cached);
+
+        let plan = plan_for("gguf:///models/tiny.gguf").unwrap();
+        assert_eq!((plan.url, plan.format), (None, Some(TokenizerFileFormat::Gguf)));
+
+        let plan = plan_for("file:///models/tokenizer.json").unwrap();
+        assert_eq!((plan.source, plan.url), (TokenizerSource::File(PathBuf::from("/models/tokenizer.json")), None));
+        assert!(plan.path.unwrap().ends_with("models/tokenizer.json"));
+
+        if !cfg!(windows) {
+            assert!(matches!(plan_for("file://remote-host/tokenizer.json"), Err(TokenizerError::UnsupportedFormat(_))));
+        }
+        assert!(matches!(plan_for("file://[broken"), Err(TokenizerError::UnsupportedFormat(_))));
+    }
+
+    #[test]
+    fn test_parse_tokenizer_source() {
+        assert_eq!(parse_tokenizer_source(""), Ok(TokenizerSource::Empty));
+        assert_eq!(parse_tokenizer_source("fake"), Ok(TokenizerSource::Fake));
+        assert_eq!(parse_tokenizer_source("fake-gpt2"), Ok(TokenizerSource::Fake));
+        assert_eq!(parse_tokenizer_source("hf://Qwen/Qwen2.5-Coder-1.5B"), Ok(TokenizerSource::Hf("Qwen/Qwen2.5-Coder-1.5B".to_string())));
+        assert_eq!(parse_tokenizer_source("http://localhost:8008/tokenizer.json"), Ok(TokenizerSource::Http("http://localhost:8008/tokenizer.json".to_string())));
+        assert_eq!(parse_tokenizer_source("gguf://models/tiny.gguf"), Ok(TokenizerSource::File(PathBuf::from("models/tiny.gguf"))));
+        assert_eq!(parse_tokenizer_source("file:///models/my%20tokenizer.json"), Ok(TokenizerSource::File(PathBuf::from("/models/my tokenizer.json"))));
+        assert_eq!(parse_tokenizer_source("models/tokenizer.json"), Ok(TokenizerSource::File(PathBuf::from("models/tokenizer.json"))));
+        if !cfg!(windows) {
+            assert!(parse_tokenizer_source("file://remote-host/tokenizer.json").is_err());
+        }
+        assert!(parse_tokenizer_source("file://[broken").is_err());
+    }
+
+    #[test]
+    fn test_file_url_to_path_windows_shapes() {
+        assert_eq!(file_url_to_path("file:///C:/models/tok/tokenizer.json"), Ok(PathBuf::from("C:/models/tok/tokenizer.json")));
+        assert_eq!(file_url_to_path("file:///d:/My%20Models/tokenizer.json"), Ok(PathBuf::from("d:/My Models/tokenizer.json")));
+        assert_eq!(file_url_to_path("file://localhost/C:/models/tokenizer.json"), Ok(PathBuf::from("C:/models/tokenizer.json")));
+        assert_eq!(file_url_to_path("file://localhost/models/tokenizer.json"), Ok(PathBuf::from("/models/tokenizer.json")));
+        assert_eq!(file_url_to_path("file:///models/tokenizer.json"), Ok(PathBuf::from("/models/tokenizer.json")));
+        let unc = file_url_to_path("file://server/share/models/tokenizer.json");
+        if cfg!(windows) {
+            assert_eq!(unc, Ok(PathBuf::from(r"\\server\share\models\tokenizer.json")));
+        } else {
+            assert!(unc.unwrap_err().contains("network share on \"server\""));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_uses_configured_tmp_dir() {
+        let base_url = spawn_http_server(|_| http_response("200 OK", &[], DUMMY_TOKENIZER.as_bytes())).await;
+        let dir = tempfile::tempdir().unwrap();
+        let tmp_dir = dir.path().join("downloads");
+        let path = dir.path().join("cache").join("tokenizer.json");
+        let seen_in_tmp_dir = Arc::new(AtomicUsize::new(0));
+        let progress: TokenizerDownloadProgress = {
+            let (tmp_dir, seen_in_tmp_dir) = (tmp_dir.clone(), seen_in_tmp_dir.clone());
+            Arc::new(move |_, _| {
+                let files = std::fs::read_dir(&tmp_dir).map(|dir| dir.count()).unwrap_or(0);
+                seen_in_tmp_dir.fetch_max(files, Ordering::SeqCst);
+            })
+        };
+        try_download_tokenizer_file_and_open(
+            &reqwest::Client::new(), &format!("{base_url}/tokenizer.json"), "", &path, None, false, &fast_policy(), Some(&tmp_dir), Some(&progress), None,
+        ).await.unwrap();
+        assert_eq!(seen_in_tmp_dir.load(Ordering::SeqCst), 1);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), DUMMY_TOKENIZER);
+    }
+
+    #[tokio::test]
+    async fn test_download_moves_tmp_file_into_place() {
+        let base_url = spawn_http_server(|_| http_response("200 OK", &[], DUMMY_TOKENIZER.as_bytes())).await;
+        let dir = tempfile::tempdir().unwrap();
+        let tmp_dir = dir.path().join("downloads");
+        let path = dir.path().join("cache").join("tokenizer.json");
+        try_download_tokenizer_file_and_open(
+            &reqwest::Client::new(), &format!("{base_url}/tokenizer.json"), "", &path, None, false, &fast_policy(), Some(&tmp_dir), None, None,
+        ).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), DUMMY_TOKENIZER);
+        assert_eq!(std::fs::read_dir(&tmp_dir).unwrap().count(), 0);
+        let cache_files: Vec<_> = std::fs::read_dir(path.parent().unwrap()).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(cache_files, vec!["tokenizer.json"]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_download_leaves_no_tmp_file() {
+        let body = DUMMY_TOKENIZER.as_bytes();
+        let base_url = spawn_http_server(move |_| {
+            let mut response = http_response("200 OK", &[], body);
+            response.truncate(response.len() - body.len() / 2);
+            response
+        }).await;
+        let dir = tempfile::tempdir().unwrap();
+        let tmp_dir = dir.path().join("downloads");
+        let path = dir.path().join("cache").join("tokenizer.json");
+        let policy = TokenizerDownloadPolicy { max_attempts: 2, ..fast_policy() };
+        assert!(try_download_tokenizer_file_and_open(
+            &reqwest::Client::new(), &format!("{base_url}/tokenizer.json"), "", &path, None, false, &policy, Some(&tmp_dir), None, None,
+        ).await.is_err());
+        assert_eq!(std::fs::read_dir(&tmp_dir).unwrap().count(), 0);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_encode_ids_matches_encode_fast() {
+        let tokenizer = dummy_tokenizer_with_special_tokens();
+        for text in ["", "hello world", "<|im_start|>user\nhi<|endoftext|>", "a\tb<tab>"] {
+            for add_special in [false, true] {
+                let expected = tokenizer.encode_fast(text, add_special).unwrap().get_ids().to_vec();
+                assert_eq!(encode_ids(&tokenizer, text, add_special).unwrap(), expected, "{text:?}");
+            }
+        }
+    }
+
+    /// Every byte is a token, a CJK character takes three
+    fn byte_level_tokenizer() -> Tokenizer {
+        use tokenizers::models::bpe::{BPE, Vocab};
+        use tokenizers::pre_tokenizers::byte_level::ByteLevel;
+        let vocab: Vocab = ByteLevel::alphabet().into_iter().enumerate()
+            .map(|(id, c)| (c.to_string(), id as u32))
+            .collect();
+        let mut tokenizer = Tokenizer::new(BPE::builder().vocab_and_merges(vocab, vec![]).build().unwrap());
+        tokenizer.with_pre_tokenizer(Some(ByteLevel::new(false, true, true)));
+        tokenizer.with_decoder(Some(tokenizers::decoders::byte_level::ByteLevel::default()));
+        tokenizer
+    }
+
+    #[test]
+    fn test_truncate_to_tokens() {
+        let tokenizer = dummy_tokenizer();
+        assert_eq!(truncate_to_tokens(&tokenizer, "hello world", 5).unwrap(), ("hello".to_string(), 5));
+        assert_eq!(truncate_to_tokens(&tokenizer, "hello world", 100).unwrap(), ("hello world".to_string(), 11));
+        assert_eq!(truncate_to_tokens(&tokenizer, "hello world", 0).unwrap(), (String::new(), 0));
+
+        let tokenizer = byte_level_tokenizer();
+        assert_eq!(truncate_to_tokens(&tokenizer, "你好世界", 7).unwrap(), ("你好".to_string(), 6));
+        assert_eq!(truncate_to_tokens(&tokenizer, "你好世界", 2).unwrap(), (String::new(), 0));
+        assert_eq!(truncate_to_tokens(&tokenizer, "你好世界", 12).unwrap(), ("你好世界".to_string(), 12));
+    }
+
+    #[test]
+    fn test_truncate_middle() {
+        let tokenizer = dummy_tokenizer();
+        let text = "fn main() { let answer = 42; }";
+        assert_eq!(truncate_middle(&tokenizer, text, 100, "...").unwrap(), text);
+        let truncated = truncate_middle(&tokenizer, text, 13, "...").unwrap();
+        assert_eq!(truncated, "fn ma...42; }");
+        assert!(count_text_tokens(Some(&tokenizer), &truncated).unwrap() <= 13);
+
+        let tokenizer = byte_level_tokenizer();
+        let truncated = truncate_middle(&tokenizer, "你好世界你好世界", 10, "…").unwrap();
+        assert_eq!(truncated, "你…界");
+        assert!(count_tokens(&tokenizer, &truncated, false).unwrap() <= 10);
+        assert_eq!(truncate_middle(&tokenizer, "你好世界", 2, "…").unwrap(), "");
+    }
+
+    #[test]
+    fn test_hf_repo_tokenizer_source() {
+        assert_eq!(parse_tokenizer_source("hf-repo:meta-llama/Llama-3-8B"), Ok(TokenizerSource::HfRepo("meta-llama/Llama-3-8B".to_string())));
+        assert!(parse_tokenizer_source("hf-repo:").is_err());
+
+        let model_rec = BaseModelRecord { id: "llama".to_string(), tokenizer: "hf-repo:meta-llama/Llama-3-8B".to_string(), ..Default::default() };
+        let plan = resolve_tokenizer_plan(&model_rec, Path::new("/cache"), None, "http://self-hosted/$HF_MODEL/tokenizer.json").unwrap();
+        assert_eq!(plan.url.as_deref(), Some("https://huggingface.co/meta-llama/Llama-3-8B/resolve/main/tokenizer.json"));
+
+        let (source, url) = (plan.source, plan.url.unwrap());
+        let hf_token = Some("hf_secret".to_string());
+        let api_key = tokenizer_api_key(&source, &url, "", hf_token.clone());
+        assert_eq!(tokenizer_auth_header(&api_key).unwrap(), Some((AUTHORIZATION, "Bearer hf_secret".to_string())));
+        assert_eq!(tokenizer_api_key(&source, &url, "own-key", hf_token.clone()), "own-key");
+        assert_eq!(tokenizer_api_key(&source, &url, "", None), "");
+        assert_eq!(tokenizer_api_key(&TokenizerSource::Http("http://x".to_string()), "http://x", "", hf_token), "");
+    }
+
+    #[test]
+    fn test_decode_replacement_character_round_trip() {
+        assert_eq!(BYTE_LEVEL_CHAR_BYTES.keys().copied().collect::<HashSet<_>>(), ByteLevel::alphabet().into_iter().collect());
+        let tokenizer = byte_level_tokenizer();
+        for text in ["\u{FFFD}", "a\u{FFFD}b", "你\u{FFFD}好\u{FFFD}"] {
+            let ids = encode_ids(&tokenizer, text, false).unwrap();
+            assert_eq!(decode_tokens(&tokenizer, &ids, false).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn test_decode_partial_multibyte_character() {
+        let tokenizer = byte_level_tokenizer();
+        let ids = encode_ids(&tokenizer, "你好", false).unwrap();
+        assert_eq!(decode_tokens(&tokenizer, &ids, false).unwrap(), "你好");
+        assert_eq!(decode_tokens(&tokenizer, &ids[..4], false), Err(DecodeError::InvalidUtf8));
+        assert_eq!(decode_lossy(&tokenizer, &ids[..4], false).unwrap(), "你\u{FFFD}");
+        let split_in_the_middle = [&ids[..4], &encode_ids(&tokenizer, "x", false).unwrap()[..]].concat();
+        assert_eq!(decode_tokens(&tokenizer, &split_in_the_middle, false), Err(DecodeError::InvalidUtf8));
+        assert_eq!(decode_lossy(&tokenizer, &[100_000], false), Err(DecodeError::UnknownId(100_000)));
+    }
+
+    #[test]
+    fn test_stream_decoder() {
+        let tokenizer = Arc::new(byte_level_tokenizer());
+        let text = "你好, 世界!";
+        let mut decoder = StreamDecoder::new(tokenizer.clone(), false);
+        let mut streamed = String::new();
+        for id in encode_ids(&tokenizer, text, false).unwrap() {
+            if let Some(piece) = decoder.push(id).unwrap() {
+                assert!(!piece.contains(char::REPLACEMENT_CHARACTER), "{piece:?}");
+                streamed.push_str(&piece);
+            }
+        }
+        streamed.push_str(&decoder.finish().unwrap());
+        assert_eq!(streamed, text);
+
+        let mut decoder = StreamDecoder::new(tokenizer.clone(), false);
+        let ids = encode_ids(&tokenizer, "你", false).unwrap();
+        assert_eq!(decoder.push(ids[0]).unwrap(), None);
+        assert_eq!(decoder.push(ids[1]).unwrap(), None);
+        assert_eq!(decoder.finish().unwrap(), "\u{FFFD}");
+    }
+
+    #[test]
+    fn test_manifest_fills_only_empty_tokenizers() {
+        let manifest = TokenizerManifest::from_json_str(r#"[{"model": "qwen*", "tokenizer": "hf://Qwen/Qwen2-7B"}]"#).unwrap();
+        let explicit = BaseModelRecord { id: "qwen2".to_string(), tokenizer: "fake".to_string(), ..Default::default() };
+        assert_eq!(with_manifest_tokenizer(&explicit, Some(&manifest)).tokenizer, "fake");
+        let empty = BaseModelRecord { id: "qwen2".to_string(), ..Default::default() };
+        assert_eq!(with_manifest_tokenizer(&empty, Some(&manifest)).tokenizer, "hf://Qwen/Qwen2-7B");
+        assert_eq!(with_manifest_tokenizer(&empty, None).tokenizer, "");
+        let unmatched = BaseModelRecord { id: "llama3".to_string(), ..Default::default() };
+        assert_eq!(with_manifest_tokenizer(&unmatched, Some(&manifest)).tokenizer, "");
+    }
+
+    #[tokio::test]
+    async fn test_tokenizer_metrics() {
+        let base_url = spawn_http_server(|request| {
+            if request.starts_with("GET /tokenizer.json") {
+                http_response("200 OK", &[], DUMMY_TOKENIZER.as_bytes())
+            } else {
+                http_response("404 Not Found", &[], b"")
+            }
+        }).await;
+        let cache_dir = tempfile::tempdir().unwrap();
+        let tokenizer_map = AMutex::new(TokenizerCache::new(TOKENIZER_CACHE_CAPACITY));
+        let download_locks = TokenizerDownloadLocks::default();
+        let metrics = TokenizerMetrics::default();
+        let config = TokenizerRegistryConfig {
+            download_policy: TokenizerDownloadPolicy { max_attempts: 2, ..fast_policy() },
+            ..test_config(cache_dir.path(), false)
+        };
+        let load = |model_id: &'static str, url: String| {
+            let (config, metrics) = (&config, &metrics);
+            async move {
+                let model_rec = BaseModelRecord { id: model_id.to_string(), tokenizer: url, ..Default::default() };
+                load_tokenizer(&model_rec, model_id, config, metrics).await
+            }
+        };
+
+        for _ in 0..3 {
+            get_or_load_tokenizer(&tokenizer_map, &download_locks, &metrics, "good", || load("good", format!("{base_url}/tokenizer.json"))).await.unwrap();
+        }
+        assert!(get_or_load_tokenizer(&tokenizer_map, &download_locks, &metrics, "bad", || load("bad", format!("{base_url}/missing.json"))).await.is_err());
+        assert_eq!(metrics.snapshot(), TokenizerMetricsSnapshot { cache_hits: 2, cache_misses: 2, downloads: 1, download_failures: 1 });
+    }
+
+    #[test]
+    fn test_tokenizer_variants_are_memoized() {
+        let variants = TokenizerVariants::new(Arc::new(dummy_tokenizer()));
+        let truncation = || Some(TruncationParams { max_length: 4, ..Default::default() });
+        let first = variants.with_truncation(truncation()).unwrap();
+        let second = variants.with_truncation(truncation()).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(first.encode_fast("abcdefgh", false).unwrap().len(), 4);
+
+        let longer = variants.with_truncation(Some(TruncationParams { max_length: 6, ..Default::default() })).unwrap();
+        assert!(!Arc::ptr_eq(&first, &longer));
+        let padded = variants.with_padding(Some(PaddingParams { strategy: PaddingStrategy::Fixed(10), ..Default::default() })).unwrap();
+        assert!(padded.get_truncation().is_none());
+        assert!(variants.with_truncation(Some(TruncationParams { max_length: 0, ..Default::default() })).is_err());
+
+        // a full map drops only the least recently used variant
+        let variants = TokenizerVariants::new(Arc::new(dummy_tokenizer()));
+        let truncated_to = |max_length| variants.with_truncation(Some(TruncationParams { max_length, ..Default::default() })).unwrap();
+        let used_first = truncated_to(100);
+        let least_recently_used = truncated_to(101);
+        for max_length in 102..100 + TOKENIZER_VARIANTS_CAPACITY {
+            truncated_to(max_length);
+        }
+        assert!(Arc::ptr_eq(&used_first, &truncated_to(100)));
+        truncated_to(200);
+        assert!(Arc::ptr_eq(&used_first, &truncated_to(100)));
+        assert!(!Arc::ptr_eq(&least_recently_used, &truncated_to(101)));
+    }
+
+    #[test]
+    fn test_is_special() {
+        let tokenizer = dummy_tokenizer_with_special_tokens();
+        assert!(is_special(&tokenizer, 97));
+        assert!(is_special_str(&tokenizer, "<|endoftext|>"));
+        assert!(!is_special(&tokenizer, 99));
+        assert!(!is_special_str(&tokenizer, "<tab>"));
+        let word_id = tokenizer.token_to_id("a").unwrap();
+        assert!(!is_special(&tokenizer, word_id));
+        assert!(!is_special_str(&tokenizer, "a"));
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_cached_tokenizer_is_downloaded_again() {
+        let base_url = spawn_http_server(|_| http_response("200 OK", &[], DUMMY_TOKENIZER.as_bytes())).await;
+        let cache_dir = tempfile::tempdir().unwrap();
+        let path = tokenizer_cache_path(cache_dir.path(), "model");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, &DUMMY_TOKENIZER[..DUMMY_TOKENIZER.len() / 2]).unwrap();
+
+        let metrics = TokenizerMetrics::default();
+        let model_rec = BaseModelRecord { id: "model".to_string(), tokenizer: format!("{base_url}/tokenizer.json"), ..Default::default() };
+        let tokenizer = load_tokenizer(&model_rec, "model", &test_config(cache_dir.path(), false), &metrics).await.unwrap();
+        assert!(tokenizer.is_some());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), DUMMY_TOKENIZER);
+        assert_eq!(metrics.snapshot().downloads, 1);
+    }
+
+    #[test]
+    fn test_chat_template_from_tokenizer_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let tokenizer_path = dir.path().join("tokenizer.json");
+        assert_eq!(chat_template_from_tokenizer_config(&tokenizer_path), None);
+
+        let config_path = dir.path().join("tokenizer_config.json");
+        std::fs::write(&config_path, r#"{"model_max_length": 2048}"#).unwrap();
+        assert_eq!(chat_template_from_tokenizer_config(&tokenizer_path), None);
+
+        let template = "{% for m in messages %}<|{{ m.role }}|>{{ m.content }}{% endfor %}";
+        std::fs::write(&config_path, serde_json::json!({"chat_template": template}).to_string()).unwrap();
+        assert_eq!(chat_template_from_tokenizer_config(&tokenizer_path).as_deref(), Some(template));
+
+        std::fs::write(&config_path, serde_json::json!({"chat_template": [
+            {"name": "tool_use", "template": "tools"},
+            {"name": "default", "template": template},
+        ]}).to_string()).unwrap();
+        assert_eq!(chat_template_from_tokenizer_config(&tokenizer_path).as_deref(), Some(template));
+    }
+
+    #[test]
+    fn test_render_chat() {
+        let template = concat!(
+            "{% for message in messages %}\n",
+            "<|im_start|>{{ message['role'] }}\n{{ message['content'].strip() }}<|im_end|>\n",
+            "{% endfor %}\n",
+            "{% if add_generation_prompt %}<|im_start|>assistant\n{% endif %}",
+        );
+        let messages = vec![
+            ChatMessage::new("system".to_string(), "You are helpful.".to_string()),
+            ChatMessage::new("user".to_string(), " hi \n".to_string()),
+        ];
+        assert_eq!(
+            render_chat(template, &messages, true).unwrap(),
+            "<|im_start|>system\nYou are helpful.<|im_end|>\n<|im_start|>user\nhi<|im_end|>\n<|im_start|>assistant\n",
+        );
+        assert!(render_chat(template, &messages, false).unwrap().ends_with("hi<|im_end|>\n"));
+
+        let strict = "{% if messages[0]['role'] != 'user' %}{{ raise_exception('must start with user') }}{% endif %}";
+        assert!(render_chat(strict, &messages, false).unwrap_err().contains("must start with user"));
+        assert!(render_chat("{% for %}", &messages, false).is_err());
+    }
+
+    #[test]
+    fn test_tokenizer_override_env_var() {
+        let model_rec = BaseModelRecord { id: "override-test/model-1.5b".to_string(), tokenizer: "hf://org/model".to_string(), ..Default::default() };
+        let env_var = tokenizer_override_env_var("override-test/model-1.5b");
+        assert_eq!(env_var, "REFACT_TOKENIZER_OVERRIDE_OVERRIDE_TEST_MODEL_1_5B");
+        assert_eq!(with_tokenizer_override(&model_rec, |_| None).tokenizer, "hf://org/model");
+        assert_eq!(with_tokenizer_override(&model_rec, |_| Some(" ".to_string())).tokenizer, "hf://org/model");
+
+        let env = HashMap::from([(env_var, "/tmp/debug/tokenizer.json".to_string())]);
+        let overridden = with_tokenizer_override(&model_rec, |name| env.get(name).cloned());
+        assert_eq!(overridden.tokenizer, "/tmp/debug/tokenizer.json");
+        assert_eq!(overridden.id, model_rec.id);
+    }
+
+    #[tokio::test]
+    async fn test_registry_reads_tokenizer_override_from_its_env() {
+        let dir = tempfile::tempdir().unwrap();
+        let tokenizer_path = dir.path().join("tokenizer.json");
+        std::fs::write(&tokenizer_path, DUMMY_TOKENIZER).unwrap();
+        let env = HashMap::from([(tokenizer_override_env_var("overridden"), tokenizer_path.display().to_string())]);
+        let config = TokenizerRegistryConfig {
+            env: EnvLookup(Arc::new(move |name| env.get(name).cloned())),
+            ..test_config(&dir.path().join("cache"), true)
+        };
+        let registry = TokenizerRegistry::new(config, TOKENIZER_CACHE_CAPACITY);
+
+        let model_rec = BaseModelRecord { id: "overridden".to_string(), tokenizer: "fake".to_string(), ..Default::default() };
+        assert!(registry.get(&model_rec, "").await.unwrap().is_some());
+        let model_rec = BaseModelRecord { id: "not-overridden".to_string(), tokenizer: "fake".to_string(), ..Default::default() };
+        assert!(registry.get(&model_rec, "").await.unwrap().is_none());
+        assert!(TokenizerRegistryConfig::default().env.get("REFACT_TOKENIZER_OVERRIDE_SURELY_UNSET_IN_TESTS").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_download_compressed_tokenizer() {
+        use std::io::Write;
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(DUMMY_TOKENIZER.as_bytes()).unwrap();
+        let gzipped = gzip.finish().unwrap();
+        let zstded = zstd::encode_all(DUMMY_TOKENIZER.as_bytes(), 0).unwrap();
+        let base_url = spawn_http_server(move |request| {
+            if request.starts_with("GET /tokenizer.json.gz") {
+                http_response("200 OK", &[("Content-Encoding", "gzip")], &gzipped)
+            } else {
+                http_response("200 OK", &[], &zstded)
+            }
+        }).await;
+        let dir = tempfile::tempdir().unwrap();
+        for (i, url) in [format!("{base_url}/tokenizer.json.gz"), format!("{base_url}/tokenizer.json.zst")].iter().enumerate() {
+            let path = dir.path().join(i.to_string()).join("tokenizer.json");
+            try_download_tokenizer_file_and_open(&reqwest::Client::new(), url, "", &path, None, false, &fast_policy(), None, None, None)
+                .await.unwrap();
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), DUMMY_TOKENIZER);
+        }
+
+        let bomb = zstd::encode_all(vec![b' '; 4096].as_slice(), 0).unwrap();
+        let path = dir.path().join("bomb.json");
+        std::fs::write(&path, bomb).unwrap();
+        assert!(matches!(decompress_tokenizer_file(&path, 1024), Err(TokenizerError::TooLarge(_))));
+        std::fs::write(&path, DUMMY_TOKENIZER).unwrap();
+        assert!(!decompress_tokenizer_file(&path, 1024).unwrap());
+    }
+
+    #[test]
+    fn test_count_total_tokens() {
+        let tokenizer = Arc::new(dummy_tokenizer());
+        let chunks = ["fn main() {", "    println!(\"hi\");", "}"];
+        let separately: usize = chunks.iter()
+            .map(|chunk| count_text_tokens(Some(&tokenizer), chunk).unwrap())
+            .sum();
+        assert_eq!(count_total_tokens(Some(&tokenizer), chunks), separately);
+        let estimated: usize = chunks.iter().map(|chunk| estimate_tokens(chunk)).sum();
+        assert_eq!(count_total_tokens(None, chunks.iter().copied()), estimated);
+        assert_eq!(count_total_tokens(Some(&tokenizer), []), 0);
+    }
+
+    #[test]
+    fn test_count_text_tokens_borrows_tokenizer() {
+        let tokenizer = Arc::new(dummy_tokenizer());
+        let borrowed = Some(tokenizer.as_ref());
+        let total: usize = ["fn", "main", "()"].iter()
+            .map(|text| count_text_tokens(borrowed, text).unwrap() + count_text_tokens_with_fallback(borrowed, text))
+            .sum();
+        assert_eq!(total, 2 * 8);
+        assert_eq!(Arc::strong_count(&tokenizer), 1);
+    }
+
+    #[tokio::test]
+    async fn test_check_tokenizers() {
+        let dir = tempfile::tempdir().unwrap();
+        let tokenizer_path = dir.path().join("tokenizer.json");
+        std::fs::write(&tokenizer_path, DUMMY_TOKENIZER).unwrap();
+        let model_recs = vec![
+            BaseModelRecord { id: "good".to_string(), tokenizer: format!("file://{}", tokenizer_path.display()), ..Default::default() },
+            BaseModelRecord { id: "broken".to_string(), tokenizer: "ftp://example.com/tokenizer.json".to_string(), ..Default::default() },
+            BaseModelRecord { id: "estimated".to_string(), tokenizer: "fake".to_string(), ..Default::default() },
+        ];
+        let (config, metrics) = (test_config(dir.path(), false), TokenizerMetrics::default());
+        let report = check_tokenizers(model_recs, |model_rec| {
+            let (config, metrics) = (&config, &metrics);
+            async move {
+                load_tokenizer(&model_rec, &model_rec.id, config, metrics).await
+            }
+        }).await;
+
+        assert_eq!(report.len(), 3);
+        let (model_id, info) = &report[0];
+        assert_eq!(model_id, "good");
+        let info = info.as_ref().unwrap();
+        assert_eq!(info.vocab_size, dummy_tokenizer().get_vocab_size(true));
+        assert_eq!(report[1].0, "broken");
+        assert!(report[1].1.is_err());
+        assert_eq!(report[2].1.as_ref().unwrap().backend, "fake");
+    }
+
+    #[cfg(feature = "tokenizer-perf-tests")]
+    #[test]
+    fn test_encode_throughput() {
+        const THRESHOLD: std::time::Duration = std::time::Duration::from_secs(10);
+        let tokenizer = dummy_tokenizer();
+        let text = "fn main() {\n    println!(\"hello\");\n}\n".repeat(1024 * 1024 / 40);
+        let started = std::time::Instant::now();
+        let encoding = tokenizer.encode_fast(text.as_str(), false).unwrap();
+        let count = count_text_tokens(Some(&tokenizer), &text).unwrap();
+        let elapsed = started.elapsed();
+        assert_eq!(encoding.len(), count);
+        assert!(elapsed < THRESHOLD, "encoding and counting {} bytes took {elapsed:?}", text.len());
+    }
+
+    #[test]
+    fn test_line_token_counts() {
+        let tokenizer = dummy_tokenizer();
+        assert_eq!(line_token_counts(&tokenizer, "ab\ncde\nf").unwrap(), vec![3, 4, 1]);
+        assert_eq!(line_token_counts(&tokenizer, "ab\n").unwrap(), vec![3, 0]);
+        assert_eq!(line_token_counts(&tokenizer, "").unwrap(), vec![0]);
+
+        let tokenizer = byte_level_tokenizer();
+        let text = "中\nab\n文";
+        let counts = line_token_counts(&tokenizer, text).unwrap();
+        assert_eq!(counts, vec![4, 3, 3]);
+        assert_eq!(counts.iter().sum::<usize>(), count_tokens(&tokenizer, text, false).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_fake_chars_per_token_tokenizer() {
+        assert_eq!(parse_tokenizer_source("fake:chars_per_token=4"), Ok(TokenizerSource::FakeCharsPerToken(4)));
+        assert!(parse_tokenizer_source("fake:chars_per_token=0").is_err());
+        assert!(parse_tokenizer_source("fake:chars_per_token=four").is_err());
+        assert_eq!(parse_tokenizer_source("fake:anything-else"), Ok(TokenizerSource::Fake));
+
+        let cache_dir = tempfile::tempdir().unwrap();
+        let load = |tokenizer: &str| {
+            let model_rec = BaseModelRecord { id: "model".to_string(), tokenizer: tokenizer.to_string(), ..Default::default() };
+            let config = test_config(cache_dir.path(), true);
+            async move {
+                load_tokenizer(&model_rec, "model", &config, &TokenizerMetrics::default()).await
+            }
+        };
+        assert!(load("fake").await.unwrap().is_none());
+        let tokenizer = load("fake:chars_per_token=4").await.unwrap().unwrap();
+        for (text, expected) in [("", 0), ("abc", 1), ("abcd", 1), ("abcde", 2), ("fn main() {\n}\n", 4), ("中文字符串", 2)] {
+            assert_eq!(count_text_tokens(Some(&tokenizer), text).unwrap(), expected, "{text:?}");
+            assert_eq!(tokenizer.encode_fast(text, false).unwrap().get_ids(), vec![0; expected]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_times_out_on_silent_host() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            // accept and never answer
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+        let dir = tempfile::tempdir().unwrap();
+        // the connection is open, so it's the request timeout that gives up on it
+        let policy = TokenizerDownloadPolicy { request_timeout: Duration::from_millis(200), ..fast_policy() };
+        let started = std::time::Instant::now();
+        let err = try_download_tokenizer_file_and_open(
+            &reqwest::Client::new(), &format!("http://{addr}/tokenizer.json"), "", &dir.path().join("tokenizer.json"),
+            None, false, &policy, None, None, None,
+        ).await.unwrap_err();
+        assert!(matches!(err, TokenizerError::Download(_)), "{err}");
+        assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+    }
+
+    #[test]
+    fn test_count_tokens_for_messages_openai() {
+        assert_eq!(OpenAiModelFamily::from_model_name("gpt-3.5-turbo-0301"), OpenAiModelFamily::Gpt35Turbo0301);
+        assert_eq!(OpenAiModelFamily::from_model_name("openai/gpt-4o-mini"), OpenAiModelFamily::Gpt4);
+
+        // dummy tokenizer has one token per char
+        let tokenizer = dummy_tokenizer();
+        let messages = [
+            OpenAiMessage { role: "system", name: None, content: "Be brief." },
+            OpenAiMessage { role: "system", name: Some("example_user"), content: "Hi" },
+            OpenAiMessage { role: "user", name: None, content: "Hello" },
+        ];
+        let text_tokens = (6 + 9) + (6 + 2 + 12) + (4 + 5);
+        assert_eq!(
+            count_tokens_for_messages_openai(&tokenizer, OpenAiModelFamily::Gpt4, &messages),
+            Ok(3 + 3 * 3 + 1 + text_tokens),
+        );
+        assert_eq!(
+            count_tokens_for_messages_openai(&tokenizer, OpenAiModelFamily::Gpt35Turbo0301, &messages),
+            Ok(3 + 3 * 4 - 1 + text_tokens),
+        );
+        assert_eq!(count_tokens_for_messages_openai(&tokenizer, OpenAiModelFamily::Gpt4, &[]), Ok(3));
+    }
+
+    /// Token counts looked up in a table, for strings whose counts are known but whose tokenizer isn't here
+    struct TableCounter(HashMap<&'static str, usize>);
+
+    impl TokenCounter for TableCounter {
+        fn count(&self, text: &str) -> Result<usize, String> {
+            self.0.get(text).copied().ok_or_else(|| format!("no count for {text:?}"))
+        }
+
+        fn encode(&self, _text: &str, _add_special: bool) -> Result<Encoding, String> {
+            Err("TableCounter only counts".to_string())
+        }
+    }
+
+    #[test]
+    fn test_count_tokens_for_messages_openai_cookbook_example() {
+        // The example conversation of OpenAI's "How to count tokens with tiktoken" cookbook, which publishes
+        // 129 prompt tokens for gpt-4 and gpt-3.5-turbo-0613 and 127 for gpt-3.5-turbo-0301, as reported by the API.
+        // Both totals imply role, name and content strings of 104 cl100k tokens together; the table pins that sum,
+        // how it splits between the strings is approximate, there's no cl100k tokenizer file in the tests
+        let counter = TableCounter(HashMap::from([
+            ("system", 1),
+            ("user", 1),
+            ("example_user", 2),
+            ("example_assistant", 2),
+            ("You are a helpful, pattern-following assistant that translates corporate jargon into plain English.", 19),
+            ("New synergies will help drive top-line growth.", 10),
+            ("Things working well together will increase revenue.", 8),
+            ("Let's circle back when we have more bandwidth to touch base on opportunities for increased leverage.", 19),
+            ("Let's talk later when we're less busy about how to do better.", 15),
+            ("This late pivot means we don't have time to boil the ocean for the client deliverable.", 19),
+        ]));
+        let messages = [
+            OpenAiMessage { role: "system", name: None, content: "You are a helpful, pattern-following assistant that translates corporate jargon into plain English." },
+            OpenAiMessage { role: "system", name: Some("example_user"), content: "New synergies will help drive top-line growth." },
+            OpenAiMessage { role: "system", name: Some("example_assistant"), content: "Things working well together will increase revenue." },
+            OpenAiMessage { role: "system", name: Some("example_user"), content: "Let's circle back when we have more bandwidth to touch base on opportunities for increased leverage." },
+            OpenAiMessage { role: "system", name: Some("example_assistant"), content: "Let's talk later when we're less busy about how to do better." },
+            OpenAiMessage { role: "user", name: None, content: "This late pivot means we don't have time to boil the ocean for the client deliverable." },
+        ];
+        assert_eq!(count_tokens_for_messages_openai(&counter, OpenAiModelFamily::Gpt4, &messages), Ok(129));
+        assert_eq!(count_tokens_for_messages_openai(&counter, OpenAiModelFamily::Gpt35Turbo0301, &messages), Ok(127));
+        let missing = [OpenAiMessage { role: "tool", name: None, content: "" }];
+        assert!(count_tokens_for_messages_openai(&counter, OpenAiModelFamily::Gpt4, &missing).is_err());
+    }
+
+    #[test]
+    fn test_count_text_tokens_strict() {
+        let tokenizer = dummy_tokenizer();
+        assert_eq!(count_text_tokens_strict(Some(&tokenizer), "hello"), count_text_tokens(Some(&tokenizer), "hello"));
+        assert!(count_text_tokens_strict(None, "hello").is_err());
+        assert_eq!(count_text_tokens(None, "hello"), Ok(estimate_tokens("hello")));
+    }
+
+    #[tokio::test]
+    async fn test_vocab_and_merges_tokenizer() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("vocab.json"), r#"{"a": 0, "b": 1, "c": 2, "ab": 3, "Ġ": 4, "Ġab": 5}"#).unwrap();
+        let model_rec = BaseModelRecord { id: "gpt2-like".to_string(), tokenizer: dir.path().display().to_string(), ..Default::default() };
+        let plan = resolve_tokenizer_plan(&model_rec, dir.path(), None, "").unwrap();
+        assert_eq!(plan.format, Some(TokenizerFileFormat::VocabMerges));
+        assert!(plan.path.unwrap().ends_with("vocab.json"));
+
+        let (config, metrics) = (test_config(dir.path(), true), TokenizerMetrics::default());
+        let load = || load_tokenizer(&model_rec, "gpt2-like", &config, &metrics);
+        assert!(matches!(load().await, Err(TokenizerError::NotFound(_))));
+        std::fs::write(dir.path().join("merges.txt"), "#version: 0.2\na b\nĠ ab\n").unwrap();
+        let tokenizer = load().await.unwrap().unwrap();
+        let encoding = tokenizer.encode_fast("abc ab", false).unwrap();
+        assert_eq!(encoding.get_ids(), &[3, 2, 5]);
+        assert_eq!(tokenizer.decode(encoding.get_ids(), false).unwrap(), "abc ab");
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_download_stops_retrying() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_seen = requests.clone();
+        let base_url = spawn_http_server(move |_| {
+            requests_seen.fetch_add(1, Ordering::SeqCst);
+            http_response("503 Service Unavailable", &[], b"")
+        }).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokenizer.json");
+        let policy = TokenizerDownloadPolicy {
+            max_attempts: 15,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(100),
+            ..fast_policy()
+        };
+        let cancel = CancellationToken::new();
+        let cancel_soon = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            cancel_soon.cancel();
+        });
+        let started = std::time::Instant::now();
+        let err = try_download_tokenizer_file_and_open(
+            &reqwest::Client::new(), &format!("{base_url}/tokenizer.json"), "", &path, None, false, &policy, None, None, Some(&cancel),
+        ).await.unwrap_err();
+        assert_eq!(err, TokenizerError::Cancelled);
+        assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+        // a request sent right before the cancellation may still reach the server
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let attempts = requests.load(Ordering::SeqCst);
+        assert!((1..=3).contains(&attempts), "{attempts} attempts");
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(requests.load(Ordering::SeqCst), attempts);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_encode_fast_with_deadline() {
+        let tokenizer = dummy_tokenizer();
+        let text = "abcdefgh".repeat(100_000);
+        let err = encode_fast_with_deadline(&tokenizer, &text, false, Instant::now()).unwrap_err();
+        assert!(err.starts_with("Encoding timeout"), "{err}");
+        let encoding = encode_fast_with_deadline(&tokenizer, "hello", false, Instant::now() + Duration::from_secs(60)).unwrap();
+        assert_eq!(encoding.len(), 5);
+    }
+
+    #[test]
+    fn test_encode_deadline_expires_while_encoding() {
+        let tokenizer = byte_level_tokenizer();
+        let text = "hello world ".repeat(20_000);
+        let err = encode_fast_with_deadline(&tokenizer, &text, false, Instant::now() + Duration::from_millis(1)).unwrap_err();
+        assert!(err.starts_with("Encoding timeout"), "{err}");
+
+        let text = "hello world ".repeat(100);
+        let encoding = encode_fast_with_deadline(&tokenizer, &text, false, Instant::now() + Duration::from_secs(60)).unwrap();
+        assert_eq!(encoding.get_ids(), tokenizer.encode_fast(text.as_str(), false).unwrap().get_ids());
+    }
+
+    #[test]
+    fn test_encode_deadline_is_checked_between_pre_tokens() {
+        let tokenizer = byte_level_tokenizer();
+        let text = "hello world ".repeat(1000);
+        let checks = AtomicUsize::new(0);
+        // on time for the first pre-token, late for the second one
+        let err = tokenize_text_until(&tokenizer, &text, true, || checks.fetch_add(1, Ordering::SeqCst) > 0).unwrap_err();
+        assert!(err.starts_with("Encoding timeout"), "{err}");
+        assert_eq!(checks.load(Ordering::SeqCst), 2);
+
+        let checks = AtomicUsize::new(0);
+        let pretokenized = tokenize_text_until(&tokenizer, &text, true, || {
+            checks.fetch_add(1, Ordering::SeqCst);
+            false
+        }).unwrap();
+        let pre_tokens = pretokenized.get_splits(OffsetReferential::Original, OffsetType::Byte).len();
+        assert_eq!(checks.load(Ordering::SeqCst), pre_tokens);
+        assert_eq!(pre_tokens, 2001);  // each word and the trailing space
+    }
+
+    #[test]
+    fn test_encode_with_char_offsets() {
+        let text = "héllo 中文";
+        let tokenizer = byte_level_tokenizer();
+        let encoding = encode_with_char_offsets(&tokenizer, text, false).unwrap();
+        let byte_encoding = encode_piece(&tokenizer, text).unwrap();
+        assert_eq!(encoding.get_ids(), byte_encoding.get_ids());
+        let char_index = |byte: usize| text[..byte].chars().count();
+        for (chars, bytes) in encoding.get_offsets().iter().zip(byte_encoding.get_offsets()) {
+            assert_eq!(*chars, (char_index(bytes.0), char_index(bytes.1)));
+        }
+        // the 3 byte tokens of 中 all point at the one char
+        assert_eq!(encoding.get_offsets()[7..10], [(6, 7); 3]);
+        assert_eq!(encoding.get_offsets().last(), Some(&(7, 8)));
+    }
+
+    #[test]
+    fn test_count_empty_and_whitespace() {
+        // (text, one token per char, estimate)
+        let cases = [("", 0, 0), (" ", 1, 1), ("\n\n", 2, 1)];
+        let tokenizer = dummy_tokenizer();
+        for (text, real, estimated) in cases {
+            assert_eq!(count_text_tokens(Some(&tokenizer), text), Ok(real), "{text:?}");
+            assert_eq!(count_text_tokens(None, text), Ok(estimated), "{text:?}");
+            assert_eq!(count_text_tokens_with_fallback(None, text), estimated, "{text:?}");
+        }
+        assert_eq!(count_total_tokens(None, ["", "", ""]), 0);
+    }
+
+    #[tokio::test]
+    async fn test_data_uri_tokenizer() {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(DUMMY_TOKENIZER);
+        let data_uri = format!("data:application/json;base64,{encoded}");
+        assert_eq!(parse_tokenizer_source(&data_uri), Ok(TokenizerSource::Data(DUMMY_TOKENIZER.as_bytes().to_vec())));
+        assert_eq!(parse_tokenizer_source("data:application/json,%7B%7D"), Ok(TokenizerSource::Data(b"{}".to_vec())));
+        assert!(parse_tokenizer_source("data:application/json;base64,not base64!").is_err());
+        assert!(parse_tokenizer_source("data:application/json").is_err());
+
+        // offline and with no cache dir, so nothing but the URI itself can be used
+        let cache_dir = tempfile::tempdir().unwrap();
+        let model_rec = BaseModelRecord { id: "inline".to_string(), tokenizer: data_uri, ..Default::default() };
+        let config = test_config(&cache_dir.path().join("missing"), true);
+        let tokenizer = load_tokenizer(&model_rec, "inline", &config, &TokenizerMetrics::default()).await.unwrap().unwrap();
+        assert_eq!(count_text_tokens(Some(&tokenizer), "hello"), Ok(5));
+        assert!(!cache_dir.path().join("missing").exists());
+    }
+
+    #[tokio::test]
+    async fn test_tokenizer_registry() {
+        let dir = tempfile::tempdir().unwrap();
+        let tokenizer_path = dir.path().join("tokenizer.json");
+        std::fs::write(&tokenizer_path, DUMMY_TOKENIZER).unwrap();
+        let manifest = TokenizerManifest::from_json_str(
+            &format!(r#"[{{"model": "from-manifest", "tokenizer": "{}"}}]"#, tokenizer_path.display()),
+        ).unwrap();
+        let config = TokenizerRegistryConfig { manifest: Some(Arc::new(manifest)), ..test_config(&dir.path().join("cache"), true) };
+        let registry = TokenizerRegistry::new(config, TOKENIZER_CACHE_CAPACITY);
+
+        let model_rec = BaseModelRecord { id: "model".to_string(), tokenizer: tokenizer_path.display().to_string(), ..Default::default() };
+        let first = registry.get(&model_rec, "").await.unwrap().unwrap();
+        let second = registry.get(&model_rec, "").await.unwrap().unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        let from_manifest = BaseModelRecord { id: "from-manifest".to_string(), ..Default::default() };
+        assert!(registry.get(&from_manifest, "").await.unwrap().is_some());
+        let broken = BaseModelRecord { id: "broken".to_string(), tokenizer: "hf://org/model".to_string(), ..Default::default() };
+        assert!(matches!(registry.get(&broken, "https://example.com/$HF_MODEL").await, Err(TokenizerError::NotFound(_))));
+        assert_eq!(registry.metrics(), TokenizerMetricsSnapshot { cache_hits: 1, cache_misses: 3, downloads: 0, download_failures: 1 });
+
+        registry.invalidate("model").await.unwrap();
+        assert!(!Arc::ptr_eq(&first, &registry.get(&model_rec, "").await.unwrap().unwrap()));
+    }
+
+    #[test]
+    fn test_redact_header_value() {
+        assert_eq!(redact_header_value(&AUTHORIZATION, "Bearer hf_secret"), "Bearer <redacted>");
+        assert_eq!(redact_header_value(&AUTHORIZATION, "hf_secret"), "<redacted>");
+        assert_eq!(redact_header_value(&PROXY_AUTHORIZATION, "Basic dXNlcjpwYXNz"), "Basic <redacted>");
+        assert_eq!(redact_header_value(&HeaderName::from_static("x-api-key"), "secret"), "<redacted>");
+        assert_eq!(redact_header_value(&COOKIE, "session=secret"), "<redacted>");
+        assert_eq!(redact_header_value(&ETAG, "\"v1\""), "\"v1\"");
+    }
+
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<StdMutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl LogBuffer {
+        /// Collects everything logged on this thread until the guard is dropped
+        fn capture() -> (LogBuffer, tracing::subscriber::DefaultGuard) {
+            let logs = LogBuffer::default();
+            let writer = logs.clone();
+            let subscriber = tracing_subscriber::fmt()
+                .with_max_level(tracing::Level::DEBUG)
+                .with_ansi(false)
+                .with_writer(move || writer.clone())
+                .finish();
+            (logs, tracing::subscriber::set_default(subscriber))
+        }
+
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_fallback_errors_are_logged_rarely() {
+        struct BrokenCounter;
+        impl TokenCounter for BrokenCounter {
+            fn count(&self, _text: &str) -> Result<usize, String> {
+                Err("Encoding error: the broken counter of the log test".to_string())
+            }
+            fn encode(&self, _text: &str, _add_special: bool) -> Result<Encoding, String> {
+                Err("Encoding error: the broken counter of the log test".to_string())
+            }
+        }
+        let (logs, _guard) = LogBuffer::capture();
+        for _ in 0..150 {
+            assert_eq!(count_with_fallback(Some(&BrokenCounter), "hello"), estimate_tokens("hello"));
+        }
+        let logs = logs.text();
+        let lines: Vec<&str> = logs.lines().filter(|line| line.contains("the broken counter of the log test")).collect();
+        assert_eq!(lines.len(), 3, "{logs}");
+        assert!(lines.iter().all(|line| line.contains("ERROR")), "{logs}");
+        assert!(!lines[0].contains("repeated"), "{logs}");
+        assert!(lines[1].ends_with("(repeated 10 times)"), "{logs}");
+        assert!(lines[2].ends_with("(repeated 100 times)"), "{logs}");
+    }
+
+    #[tokio::test]
+    async fn test_authenticated_download_does_not_log_token() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let attempts2 = attempts.clone();
+        let base_url = spawn_http_server(move |request| {
+            if !request.contains("Bearer hf_very_secret") {
+                return http_response("401 Unauthorized", &[], b"");
+            }
+            match attempts2.fetch_add(1, Ordering::SeqCst) {
+                0 => http_response("500 Internal Server Error", &[], b""),
+                _ => http_response("200 OK", &[], DUMMY_TOKENIZER.as_bytes()),
+            }
+        }).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokenizer.json");
+
+        let (logs, _guard) = LogBuffer::capture();
+        let downloaded = try_download_tokenizer_file_and_open(
+            &reqwest::Client::new(), &format!("{base_url}/tokenizer.json"), "hf_very_secret", &path, None, false, &fast_policy(), None, None, None,
+        ).await.unwrap();
+        assert!(downloaded);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        let logs = logs.text();
+        assert!(logs.contains("authorization: Bearer <redacted>"), "{logs}");
+        assert!(logs.contains("500"), "{logs}");
+        assert!(!logs.contains("hf_very_secret"), "{logs}");
+    }
+
+    #[test]
+    fn test_tokenizer_candidates() {
+        assert_eq!(tokenizer_candidates(&BaseModelRecord::default()), Vec::<&str>::new());
+        let model_rec = BaseModelRecord { tokenizer: "hf://org/model".to_string(), ..Default::default() };
+        assert_eq!(tokenizer_candidates(&model_rec), vec!["hf://org/model"]);
+        let mut model_rec: BaseModelRecord = serde_json::from_value(serde_json::json!({
+            "tokenizer": "hf://org/model",
+            "tokenizer_fallbacks": ["https://example.com/tokenizer.json?a=1,2", "/models/a,b/tokenizer.json", "", "fake", "data:application/json,{}"],
+        })).unwrap();
+        assert_eq!(
+            tokenizer_candidates(&model_rec),
+            vec!["hf://org/model", "https://example.com/tokenizer.json?a=1,2", "/models/a,b/tokenizer.json", "fake", "data:application/json,{}"],
+        );
+        model_rec.tokenizer_prefer_local = true;
+        assert_eq!(
+            tokenizer_candidates(&model_rec),
+            vec!["/models/a,b/tokenizer.json", "fake", "data:application/json,{}", "hf://org/model", "https://example.com/tokenizer.json?a=1,2"],
+        );
+        model_rec.tokenizer.clear();
+        assert_eq!(tokenizer_candidates(&model_rec)[..2], ["/models/a,b/tokenizer.json", "fake"]);
+
+        let model_rec: BaseModelRecord = serde_json::from_value(serde_json::json!({"tokenizer": "/models/a,b/tokenizer.json"})).unwrap();
+        assert_eq!(tokenizer_candidates(&model_rec), vec!["/models/a,b/tokenizer.json"]);
+        assert!(model_rec.tokenizer_fallbacks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tokenizer_candidates_fallthrough() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_seen = requests.clone();
+        let base_url = spawn_http_server(move |_| {
+            requests_seen.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", &[], DUMMY_TOKENIZER.as_bytes())
+        }).await;
+        let dir = tempfile::tempdir().unwrap();
+        let tokenizer_path = dir.path().join("tokenizer.json");
+        std::fs::write(&tokenizer_path, DUMMY_TOKENIZER).unwrap();
+        let registry = TokenizerRegistry::new(test_config(&dir.path().join("cache"), false), TOKENIZER_CACHE_CAPACITY);
+
+        let missing = dir.path().join("missing.json");
+        let model_rec = BaseModelRecord {
+            id: "fallthrough".to_string(),
+            tokenizer: missing.display().to_string(),
+            tokenizer_fallbacks: vec![tokenizer_path.display().to_string()],
+            ..Default::default()
+        };
+        assert!(registry.get(&model_rec, "").await.unwrap().is_some());
+
+        let remote_first = BaseModelRecord {
+            id: "remote-first".to_string(),
+            tokenizer: format!("{base_url}/tokenizer.json"),
+            tokenizer_fallbacks: vec![tokenizer_path.display().to_string()],
+            ..Default::default()
+        };
+        assert!(registry.get(&remote_first, "").await.unwrap().is_some());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        let local_first = BaseModelRecord { id: "local-first".to_string(), tokenizer_prefer_local: true, ..remote_first };
+        assert!(registry.get(&local_first, "").await.unwrap().is_some());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        let all_missing = BaseModelRecord {
+            id: "all-missing".to_string(),
+            tokenizer: missing.display().to_string(),
+            tokenizer_fallbacks: vec![dir.path().join("also-missing.json").display().to_string()],
+            ..Default::default()
+        };
+        let err = registry.get(&all_missing, "").await.unwrap_err();
+        assert!(matches!(&err, TokenizerError::NotFound(path) if path.contains("also-missing.json")), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_remote_tokenizer_candidates_are_cached_apart() {
+        let base_url = spawn_http_server(|request| match request.contains("GET /first.json") {
+            true => http_response("200 OK", &[], DUMMY_TOKENIZER.as_bytes()),
+            false => http_response("200 OK", &[], byte_level_tokenizer().to_string(false).unwrap().as_bytes()),
+        }).await;
+        let dir = tempfile::tempdir().unwrap();
+        let registry = TokenizerRegistry::new(test_config(dir.path(), false), TOKENIZER_CACHE_CAPACITY);
+
+        let (first, second) = (format!("{base_url}/first.json"), format!("{base_url}/second.json"));
+        let first_path = tokenizer_candidate_cache_path(dir.path(), "model", &first);
+        let second_path = tokenizer_candidate_cache_path(dir.path(), "model", &second);
+        assert_ne!(first_path, second_path);
+        assert_eq!(first_path.parent().unwrap().parent(), tokenizer_cache_path(dir.path(), "model").parent());
+
+        let first_wins = BaseModelRecord { id: "model".to_string(), tokenizer: first.clone(), tokenizer_fallbacks: vec![second.clone()], ..Default::default() };
+        registry.get(&first_wins, "").await.unwrap().unwrap();
+        assert_eq!(std::fs::read_to_string(&first_path).unwrap(), DUMMY_TOKENIZER);
+        registry.invalidate("model").await.unwrap();
+        assert!(!first_path.exists());
+
+        // the first one is cached, but the second one is asked for first now and has its own file
+        std::fs::create_dir_all(first_path.parent().unwrap()).unwrap();
+        std::fs::write(&first_path, DUMMY_TOKENIZER).unwrap();
+        let second_wins = BaseModelRecord { tokenizer: second, tokenizer_fallbacks: vec![first], ..first_wins };
+        let tokenizer = registry.get(&second_wins, "").await.unwrap().unwrap();
+        assert_eq!(tokenizer.get_vocab_size(true), byte_level_tokenizer().get_vocab_size(true));
+        assert_ne!(tokenizer.get_vocab_size(true), dummy_tokenizer().get_vocab_size(true));
+        assert_eq!(std::fs::read_to_string(&first_path).unwrap(), DUMMY_TOKENIZER);
+        assert!(second_path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_chat_template_comes_from_the_loaded_candidate() {
+        let dir = tempfile::tempdir().unwrap();
+        let tokenizer_path = dir.path().join("tokenizer.json");
+        std::fs::write(&tokenizer_path, DUMMY_TOKENIZER).unwrap();
+        std::fs::write(dir.path().join("tokenizer_config.json"), r#"{"chat_template": "{{ messages }}"}"#).unwrap();
+        let registry = TokenizerRegistry::new(test_config(&dir.path().join("cache"), false), TOKENIZER_CACHE_CAPACITY);
+        let model_rec = BaseModelRecord {
+            id: "model".to_string(),
+            tokenizer: dir.path().join("other").join("tokenizer.json").display().to_string(),
+            tokenizer_fallbacks: vec![tokenizer_path.display().to_string()],
+            ..Default::default()
+        };
+
+        let plan = registry.loaded_tokenizer_plan(&model_rec, "").unwrap();
+        assert_eq!(plan.path.and_then(|path| chat_template_from_tokenizer_config(&path)), None);
+        registry.get(&model_rec, "").await.unwrap().unwrap();
+        let plan = registry.loaded_tokenizer_plan(&model_rec, "").unwrap();
+        assert_eq!(plan.path.as_deref(), Some(canonical_path(tokenizer_path.to_string_lossy()).as_path()));
+        assert_eq!(plan.path.and_then(|path| chat_template_from_tokenizer_config(&path)).as_deref(), Some("{{ messages }}"));
+    }
+
+    #[test]
+    fn test_backend_kind() {
+        assert_eq!(backend_kind(&byte_level_tokenizer()), TokenizerBackend::Bpe);
+        assert_eq!(backend_kind(&fake_tokenizer(4).unwrap()), TokenizerBackend::WordLevel);
+        let tokenizer: Option<Arc<Tokenizer>> = Some(Arc::new(byte_level_tokenizer()));
+        assert_eq!(tokenizer.as_deref().map(backend_kind).map(TokenizerBackend::as_str), Some("BPE"));
+    }
+
+    #[test]
+    fn test_check_json_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokenizer.json");
+        assert!(matches!(check_json_file(&path), Err(TokenizerError::NotFound(_))));
+        assert!(matches!(check_json_file(dir.path()), Err(TokenizerError::Io(_))));
+        std::fs::write(&path, "{\"version\": ").unwrap();
+        assert!(matches!(check_json_file(&path), Err(TokenizerError::Parse(e)) if e.contains("is not a tokenizer")));
+        let empty_vocab = r#"{"version": "1.0", "model": {"type": "WordLevel", "vocab": {}, "unk_token": "[UNK]"}}"#;
+        std::fs::write(&path, empty_vocab).unwrap();
+        assert!(check_json_file(&path).is_err());
+        std::fs::write(&path, DUMMY_TOKENIZER).unwrap();
+        assert_eq!(check_json_file(&path), Ok(()));
+    }
+
+    #[test]
+    fn test_encode_with_params_matches_tokenizer_variants() {
+        use tokenizers::processors::template::TemplateProcessing;
+        let mut with_template = dummy_tokenizer_with_special_tokens();
+        with_template.with_post_processor(Some(
+            TemplateProcessing::builder()
+                .try_single("<|endoftext|> $A").unwrap()
+                .special_tokens(vec![("<|endoftext|>", 97)])
+                .build().unwrap(),
+        ));
+        let truncations = [
+            None,
+            Some(TruncationParams { max_length: 4, ..Default::default() }),
+            Some(TruncationParams { max_length: 5, stride: 2, direction: tokenizers::TruncationDirection::Left, ..Default::default() }),
+        ];
+        let paddings = [
+            None,
+            Some(PaddingParams { strategy: PaddingStrategy::Fixed(12), ..Default::default() }),
+            Some(PaddingParams { pad_to_multiple_of: Some(8), ..Default::default() }),
+        ];
+        for tokenizer in [byte_level_tokenizer(), dummy_tokenizer(), with_template] {
+            let variants = TokenizerVariants::new(Arc::new(tokenizer.clone()));
+            for truncation in &truncations {
+                for padding in &paddings {
+                    let variant = variants.with_truncation_and_padding(truncation.clone(), padding.clone()).unwrap();
+                    for add_special in [false, true] {
+                        let expected = (**variant).encode("abc def<|endoftext|>gh", add_special).unwrap();
+                        let actual = encode_with_params(&tokenizer, "abc def<|endoftext|>gh", add_special, truncation.as_ref(), padding.as_ref()).unwrap();
+                        assert_eq!(actual.get_ids(), expected.get_ids(), "{truncation:?} {padding:?} {add_special}");
+                        assert_eq!(actual.get_attention_mask(), expected.get_attention_mask());
+                        assert_eq!(actual.get_offsets(), expected.get_offsets());
+                        assert_eq!(actual.get_overflowing().len(), expected.get_overflowing().len());
+                    }
+                }
+            }
+        }
+        let zero = TruncationParams { max_length: 0, ..Default::default() };
+        assert!(encode_with_params(&dummy_tokenizer(), "abc", false, Some(&zero), None).is_err());
+    }
+
+    #[test]
+    fn test_hf_token_and_hf_hub_cache_fallbacks() {
+        let env = HashMap::from([("HF_HOME".to_string(), "/hf-home".to_string())]);
+        assert_eq!(hf_hub_cache_dir(|name| env.get(name).cloned()), Some(PathBuf::from("/hf-home/hub")));
+        let env = HashMap::from([("HF_HOME".to_string(), "/hf-home".to_string()), ("HF_HUB_CACHE".to_string(), "/hub".to_string())]);
+        assert_eq!(hf_hub_cache_dir(|name| env.get(name).cloned()), Some(PathBuf::from("/hub")));
+        assert_eq!(hf_hub_cache_dir(|_| Some(String::new())), None);
+        assert_eq!(hf_hub_cache_dir(|_| None), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let (hub_cache, cache_dir) = (dir.path().join("hub"), dir.path().join("cache"));
+        let repo_dir = hub_cache.join("models--Qwen--Qwen2.5-Coder-1.5B");
+        let commit = "0123456789abcdef0123456789abcdef01234567";
+        std::fs::create_dir_all(repo_dir.join("refs")).unwrap();
+        std::fs::write(repo_dir.join("refs").join("main"), format!("{commit}\n")).unwrap();
+        std::fs::create_dir_all(repo_dir.join("snapshots").join(commit)).unwrap();
+        let snapshot = repo_dir.join("snapshots").join(commit).join("tokenizer.json");
+        std::fs::write(&snapshot, DUMMY_TOKENIZER).unwrap();
+
+        let model_rec = BaseModelRecord { id: "qwen".to_string(), tokenizer: "hf://Qwen/Qwen2.5-Coder-1.5B".to_string(), ..Default::default() };
+        let plan = resolve_tokenizer_plan(&model_rec, &cache_dir, Some(&hub_cache), &default_hf_tokenizer_template()).unwrap();
+        assert_eq!((plan.url, plan.path), (None, Some(snapshot.clone())));
+        let repo_rec = BaseModelRecord { tokenizer: "hf-repo:Qwen/Qwen2.5-Coder-1.5B".to_string(), ..model_rec.clone() };
+        let plan = resolve_tokenizer_plan(&repo_rec, &cache_dir, Some(&hub_cache), "").unwrap();
+        assert_eq!((plan.url, plan.path), (None, Some(snapshot.clone())));
+        let pinned_rec = BaseModelRecord { tokenizer_revision: Some(commit.to_string()), ..model_rec.clone() };
+        let plan = resolve_tokenizer_plan(&pinned_rec, &cache_dir, Some(&hub_cache), &default_hf_tokenizer_template()).unwrap();
+        assert_eq!(plan.path, Some(snapshot));
+        // not in the hub cache: downloaded into the engine cache, never into the hub
+        let other_revision_rec = BaseModelRecord { tokenizer_revision: Some("v2".to_string()), ..model_rec.clone() };
+        let plan = resolve_tokenizer_plan(&other_revision_rec, &cache_dir, Some(&hub_cache), &default_hf_tokenizer_template()).unwrap();
+        assert!(plan.url.is_some_and(|url| url.contains("/resolve/v2/")));
+        assert_eq!(plan.path, Some(tokenizer_cache_path(&cache_dir, "qwen")));
+        let plan = resolve_tokenizer_plan(&model_rec, &cache_dir, None, &default_hf_tokenizer_template()).unwrap();
+        assert_eq!(plan.path, Some(tokenizer_cache_path(&cache_dir, "qwen")));
+        let http_rec = BaseModelRecord { tokenizer: "http://self-hosted/tokenizer.json".to_string(), ..model_rec.clone() };
+        let plan = resolve_tokenizer_plan(&http_rec, &cache_dir, Some(&hub_cache), "").unwrap();
+        assert_eq!(plan.path, Some(tokenizer_cache_path(&cache_dir, "qwen")));
+        // a mirror may serve other files under the same names, the hub cache is only for huggingface.co
+        let plan = resolve_tokenizer_plan(&model_rec, &cache_dir, Some(&hub_cache), "http://mirror/$HF_MODEL/$HF_FILENAME").unwrap();
+        assert_eq!(plan.url.as_deref(), Some("http://mirror/Qwen/Qwen2.5-Coder-1.5B/tokenizer.json"));
+        assert_eq!(plan.path, Some(tokenizer_cache_path(&cache_dir, "qwen")));
+        let plan = resolve_tokenizer_plan(&repo_rec, &cache_dir, Some(&hub_cache), "http://mirror/$HF_MODEL/$HF_FILENAME").unwrap();
+        assert_eq!((plan.url, plan.path), (None, Some(repo_dir.join("snapshots").join(commit).join("tokenizer.json"))));
+
+        // revisions and refs that aren't a commit or a plain ref name are not followed out of the repo dir
+        std::fs::write(repo_dir.join("snapshots").join(commit).join("secret.json"), "{}").unwrap();
+        let lookup = |revision: Option<&str>, filename: Option<&str>| hf_hub_cached_file(&hub_cache, "Qwen/Qwen2.5-Coder-1.5B", revision, filename);
+        assert!(lookup(Some(commit), Some("secret.json")).is_some());
+        assert!(lookup(Some(&format!("../../models--Qwen--Qwen2.5-Coder-1.5B/snapshots/{commit}")), None).is_none());
+        assert!(lookup(Some("refs/main"), None).is_none());
+        assert!(lookup(Some(""), None).is_none());
+        assert!(lookup(None, Some("../../../secret.json")).is_none());
+        std::fs::write(repo_dir.join("refs").join("escape"), format!("../snapshots/{commit}")).unwrap();
+        assert!(lookup(Some("escape"), None).is_none());
+        std::fs::create_dir_all(repo_dir.join("snapshots").join("v1")).unwrap();
+        std::fs::write(repo_dir.join("snapshots").join("v1").join("tokenizer.json"), DUMMY_TOKENIZER).unwrap();
+        assert!(lookup(Some("v1"), None).is_none());
+
+        let source = TokenizerSource::Hf("Qwen/Qwen2.5-Coder-1.5B".to_string());
+        let hf_token = Some("hf_secret".to_string());
+        let hf_url = "https://huggingface.co/Qwen/Qwen2.5-Coder-1.5B/resolve/main/tokenizer.json";
+        assert_eq!(tokenizer_api_key(&source, hf_url, "", hf_token.clone()), "hf_secret");
+        assert_eq!(tokenizer_api_key(&source, "https://cdn-lfs.huggingface.co/tokenizer.json", "", hf_token.clone()), "hf_secret");
+        assert_eq!(tokenizer_api_key(&source, hf_url, "own-key", hf_token.clone()), "own-key");
+        assert_eq!(tokenizer_api_key(&source, "http://self-hosted/Qwen/Qwen2.5-Coder-1.5B/tokenizer.json", "", hf_token.clone()), "");
+        assert_eq!(tokenizer_api_key(&source, "https://huggingface.co.evil.com/tokenizer.json", "", hf_token), "");
+    }
+
+    #[tokio::test]
+    async fn test_registry_reads_hf_hub_cache_without_deleting_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = TokenizerRegistryConfig { hf_hub_cache: Some(dir.path().join("hub")), ..test_config(&dir.path().join("cache"), true) };
+        let registry = TokenizerRegistry::new(config, TOKENIZER_CACHE_CAPACITY);
+        let repo_dir = dir.path().join("hub/models--Qwen--Qwen2.5-Coder-1.5B");
+        let commit = "0123456789abcdef0123456789abcdef01234567";
+        std::fs::create_dir_all(repo_dir.join("refs")).unwrap();
+        std::fs::write(repo_dir.join("refs").join("main"), commit).unwrap();
+        let snapshot = repo_dir.join("snapshots").join(commit).join("tokenizer.json");
+        std::fs::create_dir_all(snapshot.parent().unwrap()).unwrap();
+        std::fs::write(&snapshot, DUMMY_TOKENIZER).unwrap();
+
+        let sha256 = format!("{:x}", Sha256::digest(DUMMY_TOKENIZER.as_bytes()));
+        let model_rec = BaseModelRecord {
+            id: "qwen".to_string(),
+            tokenizer: "hf://Qwen/Qwen2.5-Coder-1.5B".to_string(),
+            tokenizer_sha256: Some(sha256),
+            ..Default::default()
+        };
+        assert!(registry.get(&model_rec, &default_hf_tokenizer_template()).await.unwrap().is_some());
+        assert!(!tokenizer_cache_path(&registry.config.cache_dir, "qwen").exists());
+        registry.invalidate("qwen").await.unwrap();
+        assert!(snapshot.exists());
+
+        // the registry is offline, so a hub cache file with another checksum leaves nothing to load
+        let other_rec = BaseModelRecord { id: "qwen-pinned".to_string(), tokenizer_sha256: Some("0".repeat(64)), ..model_rec };
+        assert!(matches!(registry.get(&other_rec, &default_hf_tokenizer_template()).await, Err(TokenizerError::NotFound(_))));
+        assert!(snapshot.exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_slow_response_is_not_a_connect_timeout() {
+        let base_url = spawn_http_server(|_| {
+            std::thread::sleep(Duration::from_millis(300));
+            http_response("200 OK", &[], DUMMY_TOKENIZER.as_bytes())
+        }).await;
+        let dir = tempfile::tempdir().unwrap();
+        let config = TokenizerRegistryConfig {
+            download_policy: TokenizerDownloadPolicy { max_attempts: 1, connect_timeout: Duration::from_millis(100), ..fast_policy() },
+            ..test_config(dir.path(), false)
+        };
+        let registry = TokenizerRegistry::new(config, TOKENIZER_CACHE_CAPACITY);
+        let model_rec = BaseModelRecord { id: "slow".to_string(), tokenizer: format!("{base_url}/tokenizer.json"), ..Default::default() };
+        assert!(registry.get(&model_rec, "").await.unwrap().is_some());
+    }
+}
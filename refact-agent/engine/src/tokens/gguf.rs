@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use tokenizers::{AddedToken, SplitDelimiterBehavior, Tokenizer};
+use tokenizers::decoders::byte_level::ByteLevel as ByteLevelDecoder;
+use tokenizers::models::bpe::{BPE, Vocab};
+use tokenizers::pre_tokenizers::PreTokenizerWrapper;
+use tokenizers::pre_tokenizers::byte_level::ByteLevel;
+use tokenizers::pre_tokenizers::digits::Digits;
+use tokenizers::pre_tokenizers::sequence::Sequence;
+use tokenizers::pre_tokenizers::split::{Split, SplitPattern};
+
+use crate::tokens::TokenizerError;
+
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+// corrupt files can claim absurd sizes, don't try to allocate them
+const MAX_STRING_LEN: u64 = 1 << 24;
+const MAX_ARRAY_LEN: u64 = 1 << 24;
+const MAX_PREALLOCATED_ITEMS: u64 = 4096;
+// tokenizer metadata is arrays of scalars, arrays of arrays are allowed but nothing deeper
+const MAX_ARRAY_DEPTH: usize = 2;
+const TOKEN_TYPE_CONTROL: i64 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+enum GgufValue {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    String(String),
+    Array(Vec<GgufValue>),
+}
+
+struct GgufReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> GgufReader<R> {
+    fn read_exact<const N: usize>(&mut self) -> Result<[u8; N], TokenizerError> {
+        let mut buf = [0u8; N];
+        self.reader.read_exact(&mut buf)
+            .map_err(|e| TokenizerError::Parse(format!("truncated gguf header: {e}")))?;
+        Ok(buf)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, TokenizerError> {
+        Ok(u32::from_le_bytes(self.read_exact()?))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, TokenizerError> {
+        Ok(u64::from_le_bytes(self.read_exact()?))
+    }
+
+    fn read_string(&mut self) -> Result<String, TokenizerError> {
+        let len = self.read_u64()?;
+        if len > MAX_STRING_LEN {
+            return Err(TokenizerError::Parse(format!("gguf string of {len} bytes is too long")));
+        }
+        let mut buf = vec![0u8; len as usize];
+        self.reader.read_exact(&mut buf)
+            .map_err(|e| TokenizerError::Parse(format!("truncated gguf header: {e}")))?;
+        String::from_utf8(buf)
+            .map_err(|e| TokenizerError::Parse(format!("gguf string is not utf-8: {e}")))
+    }
+
+    fn read_value(&mut self, value_type: u32) -> Result<GgufValue, TokenizerError> {
+        self.read_nested_value(value_type, 0)
+    }
+
+    /// `depth` is the number of arrays the value is in
+    fn read_nested_value(&mut self, value_type: u32, depth: usize) -> Result<GgufValue, TokenizerError> {
+        Ok(match value_type {
+            0 => GgufValue::Int(u8::from_le_bytes(self.read_exact()?) as i64),
+            1 => GgufValue::Int(i8::from_le_bytes(self.read_exact()?) as i64),
+            2 => GgufValue::Int(u16::from_le_bytes(self.read_exact()?) as i64),
+            3 => GgufValue::Int(i16::from_le_bytes(self.read_exact()?) as i64),
+            4 => GgufValue::Int(u32::from_le_bytes(self.read_exact()?) as i64),
+            5 => GgufValue::Int(i32::from_le_bytes(self.read_exact()?) as i64),
+            6 => GgufValue::Float(f32::from_le_bytes(self.read_exact()?) as f64),
+            7 => GgufValue::Bool(self.read_exact::<1>()?[0] != 0),
+            8 => GgufValue::String(self.read_string()?),
+            9 => {
+                if depth >= MAX_ARRAY_DEPTH {
+                    return Err(TokenizerError::Parse(format!("gguf arrays are nested more than {MAX_ARRAY_DEPTH} deep")));
+                }
+                let item_type = self.read_u32()?;
+                let len = self.read_u64()?;
+                if len > MAX_ARRAY_LEN {
+                    return Err(TokenizerError::Parse(format!("gguf array of {len} items is too long")));
+                }
+                // the length is only a claim until the items are read
+                let mut items = Vec::with_capacity(len.min(MAX_PREALLOCATED_ITEMS) as usize);
+                for _ in 0..len {
+                    items.push(self.read_nested_value(item_type, depth + 1)?);
+                }
+                GgufValue::Array(items)
+            }
+            10 => GgufValue::Int(u64::from_le_bytes(self.read_exact()?) as i64),
+            11 => GgufValue::Int(i64::from_le_bytes(self.read_exact()?)),
+            12 => GgufValue::Float(f64::from_le_bytes(self.read_exact()?)),
+            _ => return Err(TokenizerError::Parse(format!("unknown gguf value type {value_type}"))),
+        })
+    }
+
+    /// Reads the key-value section that precedes the tensors, keeps only `tokenizer.*` keys
+    fn read_tokenizer_metadata(&mut self) -> Result<HashMap<String, GgufValue>, TokenizerError> {
+        if &self.read_exact::<4>()? != GGUF_MAGIC {
+            return Err(TokenizerError::UnsupportedFormat("not a gguf file".to_string()));
+        }
+        let version = self.read_u32()?;
+        if version < 2 {
+            return Err(TokenizerError::UnsupportedFormat(format!("gguf version {version} is not supported")));
+        }
+        let _tensor_count = self.read_u64()?;
+        let kv_count = self.read_u64()?;
+        let mut metadata = HashMap::new();
+        for _ in 0..kv_count {
+            let key = self.read_string()?;
+            let value_type = self.read_u32()?;
+            let value = self.read_value(value_type)?;
+            if key.starts_with("tokenizer.") {
+                metadata.insert(key, value);
+            }
+        }
+        Ok(metadata)
+    }
+}
+
+fn string_array(metadata: &HashMap<String, GgufValue>, key: &str) -> Result<Vec<String>, TokenizerError> {
+    match metadata.get(key) {
+        Some(GgufValue::Array(items)) => items.iter()
+            .map(|item| match item {
+                GgufValue::String(s) => Ok(s.clone()),
+                _ => Err(TokenizerError::Parse(format!("gguf {key} contains a non-string item"))),
+            })
+            .collect(),
+        Some(_) => Err(TokenizerError::Parse(format!("gguf {key} is not an array"))),
+        None => Err(TokenizerError::Parse(format!("gguf has no {key}"))),
+    }
+}
+
+const LLAMA3_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+const QWEN2_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+/// The pre-tokenizer of the model's own tokenizer.json for `tokenizer.ggml.pre`, as llama.cpp names them.
+/// Files older than that key are GPT-2
+fn pre_tokenizer(pre: Option<&str>) -> Result<PreTokenizerWrapper, TokenizerError> {
+    let byte_level = |use_regex| PreTokenizerWrapper::from(ByteLevel::new(false, true, use_regex));
+    let split_then_byte_level = |pattern: &str| -> Result<PreTokenizerWrapper, TokenizerError> {
+        let split = Split::new(SplitPattern::Regex(pattern.to_string()), SplitDelimiterBehavior::Isolated, false)
+            .map_err(|e| TokenizerError::Parse(format!("failed to build gguf pre-tokenizer: {e}")))?;
+        Ok(Sequence::new(vec![split.into(), byte_level(false)]).into())
+    };
+    Ok(match pre {
+        None | Some("gpt-2" | "gpt2" | "mpt" | "olmo") => byte_level(true),
+        Some("starcoder" | "refact" | "smollm" | "codeshell" | "command-r") => {
+            Sequence::new(vec![Digits::new(true).into(), byte_level(true)]).into()
+        }
+        Some("llama3" | "llama-bpe") => split_then_byte_level(LLAMA3_PATTERN)?,
+        Some("qwen2") => split_then_byte_level(QWEN2_PATTERN)?,
+        Some(pre) => return Err(TokenizerError::UnsupportedFormat(format!("gguf pre-tokenizer \"{pre}\" is not supported"))),
+    })
+}
+
+fn build_tokenizer(metadata: &HashMap<String, GgufValue>) -> Result<Tokenizer, TokenizerError> {
+    let model = match metadata.get("tokenizer.ggml.model") {
+        Some(GgufValue::String(model)) => model.clone(),
+        _ => return Err(TokenizerError::Parse("gguf has no tokenizer.ggml.model".to_string())),
+    };
+    if model != "gpt2" {
+        return Err(TokenizerError::UnsupportedFormat(format!(
+            "gguf tokenizer model \"{model}\", only \"gpt2\" byte-level BPE is supported"
+        )));
+    }
+
+    let pre_tokenizer = match metadata.get("tokenizer.ggml.pre") {
+        Some(GgufValue::String(pre)) => pre_tokenizer(Some(pre))?,
+        Some(_) => return Err(TokenizerError::Parse("gguf tokenizer.ggml.pre is not a string".to_string())),
+        None => pre_tokenizer(None)?,
+    };
+
+    let tokens = string_array(metadata, "tokenizer.ggml.tokens")?;
+    let merges = string_array(metadata, "tokenizer.ggml.merges")?.into_iter()
+        .map(|merge| merge.split_once(' ')
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+            .ok_or_else(|| TokenizerError::Parse(format!("gguf merge \"{merge}\" is not a pair"))))
+        .collect::<Result<Vec<_>, _>>()?;
+    let vocab: Vocab = tokens.iter().enumerate()
+        .map(|(id, token)| (token.clone(), id as u32))
+        .collect();
+    let bpe = BPE::builder()
+        .vocab_and_merges(vocab, merges)
+        .build()
+        .map_err(|e| TokenizerError::Parse(format!("failed to build BPE from gguf: {e}")))?;
+
+    let mut tokenizer = Tokenizer::new(bpe);
+    tokenizer.with_pre_tokenizer(Some(pre_tokenizer));
+    tokenizer.with_decoder(Some(ByteLevelDecoder::default()));
+
+    if let Some(GgufValue::Array(token_types)) = metadata.get("tokenizer.ggml.token_type") {
+        let control_tokens: Vec<AddedToken> = token_types.iter().zip(tokens.iter())
+            .filter(|(token_type, _)| **token_type == GgufValue::Int(TOKEN_TYPE_CONTROL))
+            .map(|(_, token)| AddedToken::from(token.clone(), true))
+            .collect();
+        tokenizer.add_special_tokens(&control_tokens);
+    }
+    Ok(tokenizer)
+}
+
+/// Builds a tokenizer from the metadata of a GGUF model file, the tensors are never read
+pub fn tokenizer_from_gguf(path: &Path) -> Result<Tokenizer, TokenizerError> {
+    let file = File::open(path)
+        .map_err(|e| TokenizerError::Io(format!("failed to open {}: {}", path.display(), e)))?;
+    let metadata = GgufReader { reader: BufReader::new(file) }.read_tokenizer_metadata()?;
+    build_tokenizer(&metadata)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokenizers::{OffsetReferential, OffsetType, PreTokenizedString, PreTokenizer};
+
+    fn push_string(buf: &mut Vec<u8>, s: &str) {
+        buf.extend_from_slice(&(s.len() as u64).to_le_bytes());
+        buf.extend_from_slice(s.as_bytes());
+    }
+
+    fn push_string_array(buf: &mut Vec<u8>, key: &str, items: &[&str]) {
+        push_string(buf, key);
+        buf.extend_from_slice(&9u32.to_le_bytes());
+        buf.extend_from_slice(&8u32.to_le_bytes());
+        buf.extend_from_slice(&(items.len() as u64).to_le_bytes());
+        for item in items {
+            push_string(buf, item);
+        }
+    }
+
+    /// A gguf header with a 4-token gpt2 tokenizer, followed by garbage instead of tensors
+    fn tiny_gguf(tokenizer_model: &str) -> Vec<u8> {
+        tiny_gguf_with_pre(tokenizer_model, None)
+    }
+
+    fn tiny_gguf_with_pre(tokenizer_model: &str, pre: Option<&str>) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(GGUF_MAGIC);
+        buf.extend_from_slice(&3u32.to_le_bytes());
+        buf.extend_from_slice(&1u64.to_le_bytes());
+        buf.extend_from_slice(&(6 + pre.is_some() as u64).to_le_bytes());
+
+        push_string(&mut buf, "general.architecture");
+        buf.extend_from_slice(&8u32.to_le_bytes());
+        push_string(&mut buf, "gpt2");
+        push_string(&mut buf, "general.alignment");
+        buf.extend_from_slice(&4u32.to_le_bytes());
+        buf.extend_from_slice(&32u32.to_le_bytes());
+        push_string(&mut buf, "tokenizer.ggml.model");
+        buf.extend_from_slice(&8u32.to_le_bytes());
+        push_string(&mut buf, tokenizer_model);
+        if let Some(pre) = pre {
+            push_string(&mut buf, "tokenizer.ggml.pre");
+            buf.extend_from_slice(&8u32.to_le_bytes());
+            push_string(&mut buf, pre);
+        }
+        push_string_array(&mut buf, "tokenizer.ggml.tokens", &["a", "b", "ab", "<|endoftext|>"]);
+        push_string_array(&mut buf, "tokenizer.ggml.merges", &["a b"]);
+        push_string(&mut buf, "tokenizer.ggml.token_type");
+        buf.extend_from_slice(&9u32.to_le_bytes());
+        buf.extend_from_slice(&5u32.to_le_bytes());
+        buf.extend_from_slice(&4u64.to_le_bytes());
+        for token_type in [1i32, 1, 1, 3] {
+            buf.extend_from_slice(&token_type.to_le_bytes());
+        }
+
+        buf.extend_from_slice(&[0xff; 64]);
+        buf
+    }
+
+    #[test]
+    fn test_tokenizer_from_gguf() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiny.gguf");
+        std::fs::write(&path, tiny_gguf("gpt2")).unwrap();
+        let tokenizer = tokenizer_from_gguf(&path).unwrap();
+        let encoding = tokenizer.encode_fast("abab<|endoftext|>", false).unwrap();
+        assert_eq!(encoding.get_ids(), &[2, 2, 3]);
+        assert_eq!(tokenizer.decode(&[2, 0], false).unwrap(), "aba");
+    }
+
+    #[test]
+    fn test_tokenizer_from_gguf_rejects_bad_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bad.gguf");
+        std::fs::write(&path, tiny_gguf("llama")).unwrap();
+        assert!(matches!(tokenizer_from_gguf(&path), Err(TokenizerError::UnsupportedFormat(_))));
+        std::fs::write(&path, b"{\"version\": \"1.0\"}").unwrap();
+        assert!(matches!(tokenizer_from_gguf(&path), Err(TokenizerError::UnsupportedFormat(_))));
+        let truncated = tiny_gguf("gpt2")[..100].to_vec();
+        std::fs::write(&path, truncated).unwrap();
+        assert!(matches!(tokenizer_from_gguf(&path), Err(TokenizerError::Parse(_))));
+    }
+
+    #[test]
+    fn test_tokenizer_from_gguf_pre_tokenizer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiny.gguf");
+        let pieces = |pre: Option<&str>| {
+            std::fs::write(&path, tiny_gguf_with_pre("gpt2", pre)).unwrap();
+            let tokenizer = tokenizer_from_gguf(&path)?;
+            let mut pretokenized = PreTokenizedString::from("ab 1234");
+            tokenizer.get_pre_tokenizer().unwrap().pre_tokenize(&mut pretokenized).unwrap();
+            Ok::<_, TokenizerError>(pretokenized.get_splits(OffsetReferential::Original, OffsetType::Byte).into_iter()
+                .map(|(piece, _, _)| piece.to_string())
+                .collect::<Vec<_>>())
+        };
+        assert_eq!(pieces(None).unwrap(), ["ab", "Ġ1234"]);
+        assert_eq!(pieces(Some("gpt-2")).unwrap(), ["ab", "Ġ1234"]);
+        assert_eq!(pieces(Some("starcoder")).unwrap(), ["ab", "Ġ", "1", "2", "3", "4"]);
+        assert_eq!(pieces(Some("llama-bpe")).unwrap(), ["ab", "Ġ", "123", "4"]);
+        assert_eq!(pieces(Some("qwen2")).unwrap(), ["ab", "Ġ", "1", "2", "3", "4"]);
+        assert!(matches!(pieces(Some("deepseek-coder")), Err(TokenizerError::UnsupportedFormat(e)) if e.contains("deepseek-coder")));
+    }
+
+    #[test]
+    fn test_deeply_nested_arrays_are_rejected() {
+        let mut buf = Vec::new();
+        for _ in 0..MAX_ARRAY_DEPTH {
+            buf.extend_from_slice(&9u32.to_le_bytes());
+            buf.extend_from_slice(&1u64.to_le_bytes());
+        }
+        buf.extend_from_slice(&5u32.to_le_bytes());
+        buf.extend_from_slice(&1u64.to_le_bytes());
+        buf.extend_from_slice(&7i32.to_le_bytes());
+        let nested = GgufReader { reader: &buf[12..] }.read_value(9).unwrap();
+        assert_eq!(nested, GgufValue::Array(vec![GgufValue::Array(vec![GgufValue::Int(7)])]));
+        assert!(matches!(GgufReader { reader: buf.as_slice() }.read_value(9), Err(TokenizerError::Parse(e)) if e.contains("nested")));
+    }
+
+    #[test]
+    fn test_huge_array_claim_fails_without_allocating_it() {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&8u32.to_le_bytes());
+        buf.extend_from_slice(&(MAX_ARRAY_LEN).to_le_bytes());
+        push_string(&mut buf, "only one");
+        let mut reader = GgufReader { reader: buf.as_slice() };
+        assert!(matches!(reader.read_value(9), Err(TokenizerError::Parse(_))));
+    }
+}
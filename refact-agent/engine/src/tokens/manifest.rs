@@ -0,0 +1,99 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::tokens::TokenizerError;
+
+
+pub const TOKENIZER_MANIFEST_FILENAME: &str = "tokenizers.json";
+
+#[derive(Deserialize)]
+struct TokenizerManifestEntry {
+    model: String,
+    tokenizer: String,
+}
+
+/// Tokenizers for models that don't set one, e.g. `[{"model": "qwen2.5/*", "tokenizer": "hf://Qwen/Qwen2.5-Coder-1.5B"}]`.
+/// `model` is a glob over model ids, the first matching entry wins.
+#[derive(Debug, Default)]
+pub struct TokenizerManifest {
+    entries: Vec<(glob::Pattern, String)>,
+}
+
+impl TokenizerManifest {
+    pub fn from_json_str(json: &str) -> Result<Self, TokenizerError> {
+        let entries: Vec<TokenizerManifestEntry> = serde_json::from_str(json)
+            .map_err(|e| TokenizerError::Parse(format!("invalid tokenizer manifest: {e}")))?;
+        let entries = entries.into_iter()
+            .map(|entry| {
+                let pattern = glob::Pattern::new(&entry.model)
+                    .map_err(|e| TokenizerError::Parse(format!("invalid model glob \"{}\" in tokenizer manifest: {e}", entry.model)))?;
+                Ok((pattern, entry.tokenizer))
+            })
+            .collect::<Result<Vec<_>, TokenizerError>>()?;
+        Ok(TokenizerManifest { entries })
+    }
+
+    pub fn resolve(&self, model_id: &str) -> Option<&str> {
+        self.entries.iter()
+            .find(|(pattern, _)| pattern.matches(model_id))
+            .map(|(_, tokenizer)| tokenizer.as_str())
+    }
+}
+
+/// Reads `path`, or `tokenizers.json` in the config dir if no path is given. A broken manifest is logged and ignored.
+pub fn load_tokenizer_manifest(path: Option<&Path>, config_dir: &Path) -> Option<Arc<TokenizerManifest>> {
+    let default_path = config_dir.join(TOKENIZER_MANIFEST_FILENAME);
+    let path = match path {
+        Some(path) => path,
+        None if default_path.exists() => default_path.as_path(),
+        None => return None,
+    };
+    let manifest = std::fs::read_to_string(path)
+        .map_err(|e| TokenizerError::Io(format!("failed to read {}: {}", path.display(), e)))
+        .and_then(|json| TokenizerManifest::from_json_str(&json));
+    match manifest {
+        Ok(manifest) => {
+            tracing::info!("loaded {} tokenizer manifest entries from {}", manifest.entries.len(), path.display());
+            Some(Arc::new(manifest))
+        }
+        Err(e) => {
+            tracing::error!("{e}");
+            None
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_glob_matching() {
+        let manifest = TokenizerManifest::from_json_str(r#"[
+            {"model": "qwen2.5/coder-*", "tokenizer": "hf://Qwen/Qwen2.5-Coder-1.5B"},
+            {"model": "qwen*", "tokenizer": "hf://Qwen/Qwen2-7B"},
+            {"model": "*", "tokenizer": "fake"}
+        ]"#).unwrap();
+        assert_eq!(manifest.resolve("qwen2.5/coder-7b"), Some("hf://Qwen/Qwen2.5-Coder-1.5B"));
+        assert_eq!(manifest.resolve("qwen2.5/chat"), Some("hf://Qwen/Qwen2-7B"));
+        assert_eq!(manifest.resolve("llama3"), Some("fake"));
+        assert_eq!(TokenizerManifest::default().resolve("llama3"), None);
+
+        assert!(TokenizerManifest::from_json_str(r#"[{"model": "[", "tokenizer": "fake"}]"#).is_err());
+        assert!(TokenizerManifest::from_json_str(r#"{"qwen*": "fake"}"#).is_err());
+    }
+
+    #[test]
+    fn test_load_tokenizer_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(load_tokenizer_manifest(None, dir.path()).is_none());
+        std::fs::write(dir.path().join(TOKENIZER_MANIFEST_FILENAME), r#"[{"model": "*", "tokenizer": "fake"}]"#).unwrap();
+        assert_eq!(load_tokenizer_manifest(None, dir.path()).unwrap().resolve("any"), Some("fake"));
+        let broken = dir.path().join("broken.json");
+        std::fs::write(&broken, "[").unwrap();
+        assert!(load_tokenizer_manifest(Some(&broken), dir.path()).is_none());
+    }
+}
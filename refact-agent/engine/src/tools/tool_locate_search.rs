@@ -121,11 +121,11 @@ async fn _make_prompt(
     let caps = try_load_caps_quickly_if_not_present(gcx.clone(), 0).await.map_err(|x| x.message)?;
     let model_rec = resolve_chat_model(caps, &subchat_params.subchat_model)?;
     let tokenizer = crate::tokens::cached_tokenizer(gcx.clone(), &model_rec.base).await
-        .map_err(|e| ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, e)).map_err(|x| x.message)?;
+        .map_err(|e| ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())).map_err(|x| x.message)?;
     let tokens_extra_budget = (subchat_params.subchat_n_ctx as f32 * TOKENS_EXTRA_BUDGET_PERCENT) as usize;
     let mut tokens_budget: i64 = (subchat_params.subchat_n_ctx - subchat_params.subchat_max_new_tokens - subchat_params.subchat_tokens_for_rag - tokens_extra_budget) as i64;
     let final_message = problem_statement.to_string();
-    tokens_budget -= count_text_tokens_with_fallback(tokenizer.clone(), &final_message) as i64;
+    tokens_budget -= count_text_tokens_with_fallback(tokenizer.as_deref(), &final_message) as i64;
     let mut context = "".to_string();
     let mut context_files = vec![];
     for p in important_paths.iter() {
@@ -167,7 +167,7 @@ async fn _make_prompt(
                 continue;
             }
         };
-        let left_tokens = tokens_budget - count_text_tokens_with_fallback(tokenizer.clone(), &message_row) as i64;
+        let left_tokens = tokens_budget - count_text_tokens_with_fallback(tokenizer.as_deref(), &message_row) as i64;
         if left_tokens < 0 {
             continue;
         } else {
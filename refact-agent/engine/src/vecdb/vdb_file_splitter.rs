@@ -40,7 +40,7 @@ impl FileSplitter {
         let mut top_row: i32 = -1;
         let lines = text.split('\n').collect::<Vec<_>>();
         for (line_idx, line) in lines.iter().enumerate() {
-            let text_orig_tok_n = count_text_tokens_with_fallback(tokenizer.clone(), line);
+            let text_orig_tok_n = count_text_tokens_with_fallback(tokenizer.as_deref(), line);
             if top_row == -1 && text_orig_tok_n != 0 { // top lines are empty
                 top_row = line_idx as i32;
             }